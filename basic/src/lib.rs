@@ -12,6 +12,7 @@ pub mod sync;
 #[cfg(feature = "task")]
 pub mod task;
 pub mod time;
+pub mod trace;
 pub mod vm;
 
 extern crate alloc;
@@ -28,6 +29,7 @@ pub use corelib::{
 };
 pub use domain_main::domain_main;
 use ksync::Mutex;
+pub use trace::print_backtrace;
 
 pub type DomainInfoSet = Mutex<DomainInfo>;
 
@@ -38,6 +40,7 @@ pub fn domain_info() -> Arc<DomainInfoSet> {
 
 pub fn catch_unwind<F: FnOnce() -> AlienResult<R>, R>(f: F) -> AlienResult<R> {
     let res = unwinding::panic::catch_unwind(f).unwrap_or_else(|_| {
+        // the backtrace has been printed by the panic handler
         println_color!(31, "catch unwind error");
         Err(AlienError::DOMAINCRASH)
     });
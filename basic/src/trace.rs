@@ -0,0 +1,65 @@
+//! Backtraces of domain panics, printed as `domain:function+offset` with the symbols the
+//! loader keeps for each domain.
+use core::ffi::c_void;
+
+use unwinding::abi::{UnwindContext, UnwindReasonCode, _Unwind_Backtrace, _Unwind_GetIP};
+
+const MAX_FRAMES: usize = 32;
+/// Longer symbols are cut.
+const MAX_SYMBOL_LEN: usize = 160;
+
+/// The return addresses of a stack, innermost first. It does not allocate, so it can be
+/// captured when the heap of the domain is broken.
+#[derive(Debug, Copy, Clone)]
+pub struct Backtrace {
+    frames: [usize; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    pub fn capture() -> Self {
+        extern "C" fn trace(ctx: &UnwindContext<'_>, arg: *mut c_void) -> UnwindReasonCode {
+            // SAFETY: `arg` is the backtrace passed to `_Unwind_Backtrace` below
+            let backtrace = unsafe { &mut *(arg as *mut Backtrace) };
+            let pc = _Unwind_GetIP(ctx);
+            if pc == 0 || backtrace.len == MAX_FRAMES {
+                return UnwindReasonCode::END_OF_STACK;
+            }
+            backtrace.frames[backtrace.len] = pc;
+            backtrace.len += 1;
+            UnwindReasonCode::NO_REASON
+        }
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        _Unwind_Backtrace(trace, &mut backtrace as *mut Backtrace as *mut c_void);
+        backtrace
+    }
+
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+
+    /// Print a frame per line. A frame is symbolized by the call before its return address,
+    /// which may be the last instruction of the function.
+    pub fn print(&self) {
+        let mut buf = [0; MAX_SYMBOL_LEN];
+        for (index, pc) in self.frames().iter().enumerate() {
+            let symbol = corelib::symbolize(pc - 1, &mut buf).unwrap_or("?");
+            println_color!(31, "  #{} {:#x} {}", index, pc, symbol);
+        }
+    }
+}
+
+/// Print the backtrace of the current stack, called by the panic handler. The kernel
+/// prints the raw addresses if the stack cannot be walked.
+pub fn print_backtrace() {
+    let backtrace = Backtrace::capture();
+    if backtrace.frames().is_empty() {
+        corelib::backtrace(shared_heap::domain_id());
+        return;
+    }
+    println_color!(31, "backtrace:");
+    backtrace.print();
+}
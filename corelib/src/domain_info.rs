@@ -23,7 +23,11 @@ impl Display for DomainInfo {
         for (ty, files) in self.ty_list.iter() {
             writeln!(f, "Domain type: {}", ty)?;
             for file in files.iter() {
-                writeln!(f, "  - {}: {} bytes", file.name, file.size)?;
+                write!(f, "  - {}: {} bytes", file.name, file.size)?;
+                match file.compressed_size {
+                    Some(size) => writeln!(f, " ({} bytes compressed)", size)?,
+                    None => writeln!(f)?,
+                }
             }
         }
         for (id, data) in self.domain_list.iter() {
@@ -50,13 +54,23 @@ pub struct DomainDataInfo {
 pub struct DomainFileInfo {
     pub name: String,
     pub size: usize,
+    /// The size of the image if it is compressed.
+    pub compressed_size: Option<usize>,
 }
 
 impl DomainFileInfo {
     pub fn new(name: String, size: usize) -> Self {
-        Self { name, size }
+        Self {
+            name,
+            size,
+            compressed_size: None,
+        }
     }
-    pub fn from((name, size): (String, usize)) -> Self {
-        Self { name, size }
+    pub fn from((name, size, compressed_size): (String, usize, Option<usize>)) -> Self {
+        Self {
+            name,
+            size,
+            compressed_size,
+        }
     }
 }
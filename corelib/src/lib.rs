@@ -8,6 +8,7 @@ use core::any::Any;
 pub use core_impl::*;
 use interface::{DomainType, DomainTypeRaw};
 use pconst::LinuxErrno;
+use shared_heap::SharedHeapReport;
 use spin::Once;
 use task_meta::{OperationResult, TaskOperation};
 
@@ -79,6 +80,9 @@ pub trait CoreFunction: Send + Sync {
     fn sys_free_pages(&self, domain_id: u64, p: *mut u8, n: usize);
     fn sys_write_console(&self, s: &str);
     fn sys_backtrace(&self, domain_id: u64);
+    /// Write the function containing the address as `domain:function+offset` to the
+    /// buffer and return the length, `None` if no loaded domain contains the address.
+    fn sys_symbolize(&self, addr: usize, buf: &mut [u8]) -> Option<usize>;
     fn sys_trampoline_addr(&self) -> usize;
     fn sys_kernel_satp(&self) -> usize;
     fn sys_trap_from_user(&self) -> usize;
@@ -103,7 +107,8 @@ pub trait CoreFunction: Send + Sync {
     fn sys_reload_domain(&self, domain_name: &str) -> AlienResult<()>;
     fn vaddr_to_paddr_in_kernel(&self, vaddr: usize) -> AlienResult<usize>;
     fn task_op(&self, op: TaskOperation) -> AlienResult<OperationResult>;
-    fn checkout_shared_data(&self) -> AlienResult<()>;
+    /// Report the usage of the shared heap, the report is owned by the kernel
+    fn checkout_shared_data(&self) -> AlienResult<SharedHeapReport>;
 
     fn domain_info(&self) -> AlienResult<Arc<dyn Any + Send + Sync>>;
 }
@@ -114,6 +119,7 @@ mod core_impl {
     use core::any::Any;
 
     use interface::{DomainType, DomainTypeRaw};
+    use shared_heap::{SharedData, SharedHeapReport};
     use spin::Once;
     use task_meta::{TaskMeta, TaskOperation};
 
@@ -139,6 +145,22 @@ mod core_impl {
     pub fn init(syscall: &'static dyn CoreFunction) {
         clear_bss();
         CORE_FUNC.call_once(|| syscall);
+        shared_heap::init_ring_waiter(&TaskWaiter);
+    }
+
+    /// Blocks the tasks of this domain on shared-heap rings.
+    struct TaskWaiter;
+
+    impl shared_heap::RingWaiter for TaskWaiter {
+        fn current_tid(&self) -> Option<usize> {
+            current_tid_from_tp()
+        }
+        fn wait(&self) {
+            let _ = wait_now();
+        }
+        fn wake(&self, tid: usize) {
+            let _ = wake_up_wait_task(tid);
+        }
     }
 
     pub fn alloc_raw_pages(n: usize, domain_id: u64) -> *mut u8 {
@@ -157,6 +179,17 @@ mod core_impl {
         CORE_FUNC.get_must().sys_backtrace(domain_id);
     }
 
+    /// Find the function containing the address, see [`CoreFunction::sys_symbolize`].
+    pub fn symbolize(addr: usize, buf: &mut [u8]) -> Option<&str> {
+        let len = CORE_FUNC.get_must().sys_symbolize(addr, buf)?;
+        let buf = &buf[..len.min(buf.len())];
+        // the name may be cut in the middle of a character
+        match core::str::from_utf8(buf) {
+            Ok(s) => Some(s),
+            Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).ok(),
+        }
+    }
+
     pub fn trampoline_addr() -> usize {
         static TRAMPOLINE_ADDR: Once<usize> = Once::new();
 
@@ -305,8 +338,10 @@ mod core_impl {
             .map(|res| res.priority())
     }
 
-    pub fn checkout_shared_data() -> AlienResult<()> {
-        CORE_FUNC.get_must().checkout_shared_data()
+    pub fn checkout_shared_data() -> AlienResult<SharedHeapReport> {
+        let report = CORE_FUNC.get_must().checkout_shared_data()?;
+        report.move_to(shared_heap::domain_id());
+        Ok(report)
     }
 
     pub fn domain_info() -> AlienResult<Arc<dyn Any + Send + Sync>> {
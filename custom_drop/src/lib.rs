@@ -1,65 +1,175 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, DataStruct, DeriveInput};
+use proc_macro2::{Ident, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DataEnum, DataStruct, DeriveInput, Fields};
 
+/// Derive `CustomDrop` by calling `custom_drop` on every field of a struct or
+/// on every field of the active variant of an enum.
+///
+/// The generated impl specializes the blanket impl in `shared_heap`, so the
+/// crate using the derive must enable `#![feature(min_specialization)]`.
 #[proc_macro_derive(CustomDrop)]
 pub fn custom_drop(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let name = &input.ident;
-    // call the custom_drop method for every field
-    // of the struct
-    let gen = match &input.data {
-        syn::Data::Struct(DataStruct { fields, .. }) => {
-            match fields {
-                syn::Fields::Named(fields) => {
-                    // named fields
-                    let field_names = fields.named.iter().map(|field| {
-                        let field_name = field.ident.as_ref().unwrap();
-                        quote! {
-                            self.#field_name.custom_drop();
-                        }
-                    });
-                    quote! {
-                        impl CustomDrop for #name {
-                            fn custom_drop(&mut self) {
-                                #(#field_names)*
-                            }
-                        }
+    let body = match &input.data {
+        Data::Struct(DataStruct { fields, .. }) => {
+            // call the custom_drop method for every field
+            // of the struct
+            let calls = struct_field_access(fields).map(|field| {
+                quote! {
+                    self.#field.custom_drop();
+                }
+            });
+            quote! {
+                #(#calls)*
+            }
+        }
+        Data::Enum(DataEnum { variants, .. }) => {
+            // bind the fields of the active variant and call the
+            // custom_drop method for each of them
+            let arms = variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let (pattern, bindings) = variant_pattern(ident, &variant.fields);
+                quote! {
+                    #pattern => {
+                        #(#bindings.custom_drop();)*
                     }
                 }
-                syn::Fields::Unnamed(field) => {
-                    // unnamed fields
-                    let field_names = (0..field.unnamed.len()).map(|i| {
-                        let field_name = syn::Index::from(i);
-                        quote! {
-                            self.#field_name.custom_drop();
-                        }
-                    });
+            });
+            quote! {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#arms)*
+                    _ => {}
+                }
+            }
+        }
+        Data::Union(_) => panic!("Unions are not supported"),
+    };
+    let (header, where_clause) = impl_header(&input, quote!(::shared_heap::CustomDrop));
+    quote! {
+        #header #where_clause {
+            fn custom_drop(&mut self) {
+                #[allow(unused_imports)]
+                use ::shared_heap::CustomDrop;
+                #body
+            }
+        }
+    }
+    .into()
+}
 
-                    quote! {
-                        impl CustomDrop for #name {
-                            fn custom_drop(&mut self) {
-                                #(#field_names)*
-                            }
-                        }
+/// Derive `SharedData` by calling `move_to` on every field of a struct or on
+/// every field of the active variant of an enum.
+///
+/// The returned domain id is the last non-zero id reported by a field, so
+/// fields which do not own shared data do not hide the previous owner.
+#[proc_macro_derive(SharedData)]
+pub fn shared_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let body = match &input.data {
+        Data::Struct(DataStruct { fields, .. }) => {
+            let calls = struct_field_access(fields).map(|field| {
+                quote! {
+                    let id = self.#field.move_to(new_domain_id);
+                    if id != 0 {
+                        domain_id = id;
                     }
                 }
-                syn::Fields::Unit => {
-                    // unit struct
-                    quote! {
-                        impl CustomDrop for #name {
-                            fn custom_drop(&mut self) {
-                                // do nothing
+            });
+            quote! {
+                #(#calls)*
+            }
+        }
+        Data::Enum(DataEnum { variants, .. }) => {
+            let arms = variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let (pattern, bindings) = variant_pattern(ident, &variant.fields);
+                quote! {
+                    #pattern => {
+                        #(
+                            let id = #bindings.move_to(new_domain_id);
+                            if id != 0 {
+                                domain_id = id;
                             }
-                        }
+                        )*
                     }
                 }
+            });
+            quote! {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#arms)*
+                    _ => {}
+                }
             }
         }
-        syn::Data::Enum(_) => {
-            panic!("Enums are not supported")
+        Data::Union(_) => panic!("Unions are not supported"),
+    };
+    let (header, where_clause) = impl_header(&input, quote!(::shared_heap::SharedData));
+    quote! {
+        #header #where_clause {
+            fn move_to(&self, new_domain_id: u64) -> u64 {
+                #[allow(unused_imports)]
+                use ::shared_heap::SharedData;
+                #[allow(unused_mut)]
+                let mut domain_id = 0;
+                #body
+                domain_id
+            }
         }
-        syn::Data::Union(_) => panic!("Unions are not supported"),
+    }
+    .into()
+}
+
+/// Build `impl<..> Trait for Name<..>` with every type parameter bounded by
+/// `RRefable`, so the impl specializes the blanket one in `shared_heap`, and by
+/// `'static` as a `DBox` of the parameter requires.
+fn impl_header(input: &DeriveInput, trait_path: TokenStream2) -> (TokenStream2, TokenStream2) {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let header = quote! {
+        impl #impl_generics #trait_path for #name #ty_generics
+    };
+    let params = input.generics.type_params().map(|param| &param.ident);
+    let predicates = where_clause.map(|w| &w.predicates).into_iter();
+    let where_clause = quote! {
+        where #(#predicates,)* #(#params: ::shared_heap::RRefable + 'static,)*
     };
-    gen.into()
+    (header, where_clause)
+}
+
+/// The member expressions (`a`, `0`, ...) of every field of a struct.
+fn struct_field_access(fields: &Fields) -> impl Iterator<Item = TokenStream2> + '_ {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        })
+}
+
+/// A pattern matching the given variant and the names bound to its fields.
+fn variant_pattern(variant: &Ident, fields: &Fields) -> (TokenStream2, Vec<Ident>) {
+    match fields {
+        Fields::Named(named) => {
+            let bindings = named
+                .named
+                .iter()
+                .map(|field| field.ident.clone().unwrap())
+                .collect::<Vec<_>>();
+            (quote!(Self::#variant { #(#bindings),* }), bindings)
+        }
+        Fields::Unnamed(unnamed) => {
+            let bindings = (0..unnamed.unnamed.len())
+                .map(|i| format_ident!("__field{}", i))
+                .collect::<Vec<_>>();
+            (quote!(Self::#variant ( #(#bindings),* )), bindings)
+        }
+        Fields::Unit => (quote!(Self::#variant), vec![]),
+    }
 }
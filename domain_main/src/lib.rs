@@ -1,19 +1,48 @@
 use proc_macro2::TokenStream;
 use quote::quote;
+use syn::{
+    parse::Parser, punctuated::Punctuated, spanned::Spanned, Expr, ExprArray, Lit, MetaNameValue,
+    Token,
+};
 
+/// Mark the entry of a domain.
+///
+/// The arguments declare the manifest of the domain, read by the loader from the
+/// `.domain_manifest` section:
+///
+/// ```ignore
+/// #[domain_main(
+///     ty = CacheBlkDeviceDomain,
+///     version = "0.1.0",
+///     fingerprint = 0x1234,
+///     deps = ["blk"],
+///     heap = 0x10000,
+///     pages = 16
+/// )]
+/// fn main() -> Box<dyn CacheBlkDeviceDomain> { ... }
+/// ```
+///
+/// `name` and `version` default to the package, the numbers to 0. Without arguments no
+/// manifest is emitted.
 #[proc_macro_attribute]
 pub fn domain_main(
-    _attr: proc_macro::TokenStream,
+    attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
     let item = TokenStream::from(item);
     let panic = panic_impl();
+    let manifest = if attr.is_empty() {
+        TokenStream::new()
+    } else {
+        manifest_impl(attr.into()).unwrap_or_else(syn::Error::into_compile_error)
+    };
     quote! (
         #[global_allocator]
         static HEAP_ALLOCATOR: malloc::HeapAllocator =  malloc::HeapAllocator::new(corelib::alloc_raw_pages);
         #[no_mangle]
         #item
         #panic
+        #manifest
     )
     .into()
 }
@@ -23,7 +52,7 @@ fn panic_impl() -> TokenStream {
         #[panic_handler]
         fn panic(info: &PanicInfo) -> ! {
             basic::println_color!(31, "{:?}", info);
-            basic::backtrace(domain_id());
+            basic::print_backtrace();
             #[cfg(feature = "rust-unwind")]
             {
                 basic::unwind_from_panic();
@@ -33,3 +62,143 @@ fn panic_impl() -> TokenStream {
     )
     .into()
 }
+
+/// The manifest format read by `loader::manifest`.
+const MANIFEST_MAGIC: &[u8; 8] = b"DOMMANIF";
+const MANIFEST_FORMAT: u32 = 1;
+/// The size of the fixed fields before the name.
+const MANIFEST_HEADER_SIZE: usize = 48;
+
+fn string_lit(expr: &Expr) -> syn::Result<String> {
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            Lit::Str(s) => Ok(s.value()),
+            _ => Err(syn::Error::new(expr.span(), "expected a string")),
+        },
+        _ => Err(syn::Error::new(expr.span(), "expected a string")),
+    }
+}
+
+fn parse_version(version: &str, span: proc_macro2::Span) -> syn::Result<[u16; 3]> {
+    // ignore the pre-release and build metadata
+    let core = version.split(['-', '+']).next().unwrap();
+    let parts = core
+        .split('.')
+        .map(|part| part.parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|parts| parts.len() == 3)
+        .ok_or_else(|| syn::Error::new(span, "expected a version as \"major.minor.patch\""))?;
+    Ok([parts[0], parts[1], parts[2]])
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str, span: proc_macro2::Span) -> syn::Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| syn::Error::new(span, "the name is too long"))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn manifest_impl(attr: TokenStream) -> syn::Result<TokenStream> {
+    let args = Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse2(attr)?;
+    let mut name = std::env::var("CARGO_PKG_NAME").unwrap_or_default();
+    let mut version = std::env::var("CARGO_PKG_VERSION").unwrap_or_default();
+    let mut version_span = proc_macro2::Span::call_site();
+    let mut ty = None;
+    let zero: Expr = syn::parse_quote!(0);
+    let (mut fingerprint, mut heap, mut pages) = (zero.clone(), zero.clone(), zero);
+    let mut deps = vec![];
+    for arg in args {
+        let key = arg
+            .path
+            .get_ident()
+            .map(|ident| ident.to_string())
+            .unwrap_or_default();
+        match key.as_str() {
+            "name" => name = string_lit(&arg.value)?,
+            "version" => {
+                version = string_lit(&arg.value)?;
+                version_span = arg.value.span();
+            }
+            "ty" => ty = Some(arg.value),
+            "fingerprint" => fingerprint = arg.value,
+            "heap" => heap = arg.value,
+            "pages" => pages = arg.value,
+            "deps" => match &arg.value {
+                Expr::Array(ExprArray { elems, .. }) => {
+                    for elem in elems {
+                        deps.push((string_lit(elem)?, elem.span()));
+                    }
+                }
+                value => return Err(syn::Error::new(value.span(), "expected [\"name\", ..]")),
+            },
+            _ => {
+                return Err(syn::Error::new(
+                    arg.path.span(),
+                    "expected name, ty, version, fingerprint, deps, heap or pages",
+                ))
+            }
+        }
+    }
+    let ty = match ty {
+        // a variant of `DomainTypeRaw`
+        Some(Expr::Path(path)) if path.path.get_ident().is_some() => {
+            quote!(interface::DomainTypeRaw::#path as u64)
+        }
+        Some(ty) => quote!((#ty) as u64),
+        None => {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "the manifest needs the domain type, `ty = ...`",
+            ))
+        }
+    };
+    let version = parse_version(&version, version_span)?;
+
+    let mut buf = Vec::with_capacity(MANIFEST_HEADER_SIZE);
+    buf.extend_from_slice(MANIFEST_MAGIC);
+    buf.extend_from_slice(&MANIFEST_FORMAT.to_le_bytes());
+    // the domain type, written below
+    buf.extend_from_slice(&[0; 4]);
+    for part in version {
+        buf.extend_from_slice(&part.to_le_bytes());
+    }
+    buf.extend_from_slice(&[0; 2]);
+    // fingerprint, heap and pages, written below
+    buf.extend_from_slice(&[0; 24]);
+    assert_eq!(buf.len(), MANIFEST_HEADER_SIZE);
+    push_str(&mut buf, &name, proc_macro2::Span::call_site())?;
+    let count = u16::try_from(deps.len())
+        .map_err(|_| syn::Error::new(proc_macro2::Span::call_site(), "too many dependencies"))?;
+    buf.extend_from_slice(&count.to_le_bytes());
+    for (dep, span) in &deps {
+        push_str(&mut buf, dep, *span)?;
+    }
+    let len = buf.len();
+    Ok(quote!(
+        #[used]
+        #[no_mangle]
+        #[link_section = ".domain_manifest"]
+        static DOMAIN_MANIFEST: [u8; #len] = {
+            let mut manifest = [#(#buf),*];
+            let fields: [(usize, u64, usize); 4] = [
+                (12, #ty, 4),
+                (24, (#fingerprint) as u64, 8),
+                (32, (#heap) as u64, 8),
+                (40, (#pages) as u64, 8),
+            ];
+            let mut i = 0;
+            while i < fields.len() {
+                let (offset, value, len) = fields[i];
+                let bytes = value.to_le_bytes();
+                let mut j = 0;
+                while j < len {
+                    manifest[offset + j] = bytes[j];
+                    j += 1;
+                }
+                i += 1;
+            }
+            manifest
+        };
+    ))
+}
@@ -0,0 +1,100 @@
+#![feature(test)]
+extern crate test;
+
+use core::{alloc::Layout, any::TypeId};
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use test::{black_box, Bencher};
+
+fn drop_nothing(_: TypeId, _: *mut u8, _: Layout) {}
+
+fn alloc_dealloc(b: &mut Bencher, size: usize) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    b.iter(|| unsafe {
+        let allocation = SHARED_HEAP_ALLOCATOR
+            .alloc(layout, TypeId::of::<u8>(), drop_nothing, 1)
+            .unwrap();
+        SHARED_HEAP_ALLOCATOR.dealloc(black_box(allocation.value_pointer));
+    });
+}
+
+#[bench]
+fn alloc_dealloc_small(b: &mut Bencher) {
+    alloc_dealloc(b, 16);
+}
+
+#[bench]
+fn alloc_dealloc_packet(b: &mut Bencher) {
+    alloc_dealloc(b, 1514);
+}
+
+#[bench]
+fn alloc_dealloc_page(b: &mut Bencher) {
+    alloc_dealloc(b, 4096);
+}
+
+#[bench]
+fn alloc_dealloc_large(b: &mut Bencher) {
+    alloc_dealloc(b, 64 * 1024);
+}
+
+/// The pattern of a proxy call: the buffer is moved to the callee and back.
+#[bench]
+fn move_to_and_back(b: &mut Bencher) {
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let allocation = unsafe {
+        SHARED_HEAP_ALLOCATOR
+            .alloc(layout, TypeId::of::<u8>(), drop_nothing, 1)
+            .unwrap()
+    };
+    let ptr = allocation.value_pointer;
+    b.iter(|| unsafe {
+        let old = SHARED_HEAP_ALLOCATOR.move_to(black_box(ptr), 2);
+        SHARED_HEAP_ALLOCATOR.move_to(black_box(ptr), old);
+    });
+    unsafe { SHARED_HEAP_ALLOCATOR.dealloc(ptr) };
+}
+
+/// Churn of many live buffers, as a domain streaming packets does.
+#[bench]
+fn alloc_dealloc_burst(b: &mut Bencher) {
+    let layout = Layout::from_size_align(1514, 8).unwrap();
+    let mut ptrs = Vec::with_capacity(256);
+    b.iter(|| unsafe {
+        for _ in 0..256 {
+            let allocation = SHARED_HEAP_ALLOCATOR
+                .alloc(layout, TypeId::of::<u8>(), drop_nothing, 1)
+                .unwrap();
+            ptrs.push(allocation.value_pointer);
+        }
+        ptrs.drain(..)
+            .for_each(|ptr| SHARED_HEAP_ALLOCATOR.dealloc(ptr));
+    });
+}
+
+/// Crash cleanup of a domain holding 1024 objects while another domain holds 1024 more.
+#[bench]
+fn free_crashed_domain(b: &mut Bencher) {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let others = (0..1024)
+        .map(|_| unsafe {
+            SHARED_HEAP_ALLOCATOR
+                .alloc(layout, TypeId::of::<u8>(), drop_nothing, 1)
+                .unwrap()
+                .value_pointer
+        })
+        .collect::<Vec<_>>();
+    b.iter(|| {
+        for _ in 0..1024 {
+            unsafe {
+                SHARED_HEAP_ALLOCATOR
+                    .alloc(layout, TypeId::of::<u8>(), drop_nothing, 3)
+                    .unwrap();
+            }
+        }
+        free_domain_shared_data(3, FreeShared::Free);
+    });
+    others
+        .into_iter()
+        .for_each(|ptr| unsafe { SHARED_HEAP_ALLOCATOR.dealloc(ptr) });
+}
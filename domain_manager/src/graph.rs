@@ -0,0 +1,220 @@
+//! The dependency graph of domains.
+//!
+//! A domain uses the domains it depends on at init, e.g. a cache block device opens its
+//! block device by name. The kernel adds each domain with the dependencies declared in its
+//! manifest, starts them in [`DomainGraph::boot_order`] and reinitializes the dependents
+//! of a domain which is replaced.
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+/// Take the order out of the lock before starting the domains, a domain may look up the
+/// graph at init.
+pub static DOMAIN_GRAPH: Mutex<DomainGraph> = Mutex::new(DomainGraph::new());
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum GraphError {
+    /// The domain is already in the graph.
+    Duplicate(String),
+    /// The domain is not in the graph.
+    NotFound(String),
+    /// The domain depends on a domain which is not in the graph.
+    MissingDependency { domain: String, dependency: String },
+    /// Each domain depends on the next one and the last one on the first.
+    Cycle(Vec<String>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BootError<E> {
+    Graph(GraphError),
+    /// The domain failed to start, the domains after it are not started.
+    Start {
+        domain: String,
+        error: E,
+    },
+}
+
+impl<E> From<GraphError> for BootError<E> {
+    fn from(value: GraphError) -> Self {
+        BootError::Graph(value)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Mark {
+    Visiting,
+    Done,
+}
+
+pub struct DomainGraph {
+    /// The dependencies of each domain.
+    dependencies: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for DomainGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DomainGraph {
+    pub const fn new() -> Self {
+        Self {
+            dependencies: BTreeMap::new(),
+        }
+    }
+
+    /// Add the domain, its dependencies may be added later.
+    pub fn add_domain<S: AsRef<str>>(
+        &mut self,
+        name: &str,
+        dependencies: &[S],
+    ) -> Result<(), GraphError> {
+        if self.dependencies.contains_key(name) {
+            return Err(GraphError::Duplicate(name.to_string()));
+        }
+        let dependencies = dependencies
+            .iter()
+            .map(|dependency| dependency.as_ref().to_string())
+            .collect();
+        self.dependencies.insert(name.to_string(), dependencies);
+        Ok(())
+    }
+
+    pub fn remove_domain(&mut self, name: &str) -> bool {
+        self.dependencies.remove(name).is_some()
+    }
+
+    pub fn dependencies(&self, name: &str) -> Option<&[String]> {
+        self.dependencies
+            .get(name)
+            .map(|dependencies| dependencies.as_slice())
+    }
+
+    /// Order the domains so that each one comes after its dependencies. The order is
+    /// stable, domains without dependencies between them are sorted by name.
+    pub fn boot_order(&self) -> Result<Vec<String>, GraphError> {
+        let mut marks = BTreeMap::new();
+        let mut order = vec![];
+        for name in self.dependencies.keys() {
+            self.visit(name, &mut marks, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// Depth-first walk from the domain, pushing each domain after its dependencies. The
+    /// stack is explicit so a long chain cannot overflow the kernel stack.
+    fn visit<'a>(
+        &'a self,
+        name: &'a str,
+        marks: &mut BTreeMap<&'a str, Mark>,
+        order: &mut Vec<String>,
+    ) -> Result<(), GraphError> {
+        if marks.contains_key(name) {
+            return Ok(());
+        }
+        marks.insert(name, Mark::Visiting);
+        // the domains being visited with the index of their next dependency
+        let mut stack = vec![(name, 0)];
+        while let Some((domain, next)) = stack.last_mut() {
+            let domain = *domain;
+            let dependency = match self.dependencies[domain].get(*next) {
+                Some(dependency) => dependency.as_str(),
+                None => {
+                    marks.insert(domain, Mark::Done);
+                    order.push(domain.to_string());
+                    stack.pop();
+                    continue;
+                }
+            };
+            *next += 1;
+            if !self.dependencies.contains_key(dependency) {
+                return Err(GraphError::MissingDependency {
+                    domain: domain.to_string(),
+                    dependency: dependency.to_string(),
+                });
+            }
+            match marks.get(dependency) {
+                Some(Mark::Done) => {}
+                Some(Mark::Visiting) => {
+                    let start = stack
+                        .iter()
+                        .position(|(domain, _)| *domain == dependency)
+                        .unwrap();
+                    let cycle = stack[start..]
+                        .iter()
+                        .map(|(domain, _)| domain.to_string())
+                        .collect();
+                    return Err(GraphError::Cycle(cycle));
+                }
+                None => {
+                    marks.insert(dependency, Mark::Visiting);
+                    stack.push((dependency, 0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The domains which depend on the domain, directly or not, in boot order.
+    pub fn dependents(&self, name: &str) -> Result<Vec<String>, GraphError> {
+        if !self.dependencies.contains_key(name) {
+            return Err(GraphError::NotFound(name.to_string()));
+        }
+        let mut dependents = BTreeSet::new();
+        let mut queue = vec![name];
+        while let Some(domain) = queue.pop() {
+            for (dependent, dependencies) in self.dependencies.iter() {
+                if dependencies.iter().any(|dependency| dependency == domain)
+                    && dependents.insert(dependent.as_str())
+                {
+                    queue.push(dependent);
+                }
+            }
+        }
+        let order = self.boot_order()?;
+        Ok(order
+            .into_iter()
+            .filter(|domain| domain != name && dependents.contains(domain.as_str()))
+            .collect())
+    }
+
+    /// Start every domain after its dependencies, stop at the first one which fails.
+    pub fn boot<E, F>(&self, start: F) -> Result<(), BootError<E>>
+    where
+        F: FnMut(&str) -> Result<(), E>,
+    {
+        run(&self.boot_order()?, start)
+    }
+
+    /// Restart the domain, then reinitialize its dependents in boot order so they use the
+    /// new instance. Return the domains which were restarted.
+    pub fn restart<E, F>(&self, name: &str, restart: F) -> Result<Vec<String>, BootError<E>>
+    where
+        F: FnMut(&str) -> Result<(), E>,
+    {
+        let mut domains = self.dependents(name)?;
+        domains.insert(0, name.to_string());
+        run(&domains, restart)?;
+        Ok(domains)
+    }
+}
+
+fn run<E, F>(domains: &[String], mut start: F) -> Result<(), BootError<E>>
+where
+    F: FnMut(&str) -> Result<(), E>,
+{
+    for domain in domains {
+        log::info!("start domain: {}", domain);
+        start(domain).map_err(|error| BootError::Start {
+            domain: domain.clone(),
+            error,
+        })?;
+    }
+    Ok(())
+}
@@ -3,6 +3,9 @@
 #![no_std]
 extern crate alloc;
 
+pub mod graph;
+pub mod namespace;
+pub mod persist;
 pub mod resource;
 pub mod sheap;
 pub mod storage_heap;
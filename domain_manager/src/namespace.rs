@@ -0,0 +1,168 @@
+//! Storage namespaces shared by several domains, such as the instances of a file system
+//! sharing a cache.
+//!
+//! A namespace is registered by the kernel with the access of each domain. It is not
+//! owned by any domain, so it keeps its values when one of them crashes or is replaced.
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use storage::{
+    CustomStorge, DomainDataStorage, SharedStorage, StorageEntry, StorageError, StorageResult,
+    TypeTag,
+};
+
+use crate::storage_heap::{DomainDataMap, DOMAIN_DATA_ALLOCATOR};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+#[derive(Clone)]
+struct Namespace {
+    data: DomainDataMap,
+    access: Arc<Mutex<BTreeMap<u64, Access>>>,
+}
+
+static NAMESPACES: Mutex<BTreeMap<String, Namespace>> = Mutex::new(BTreeMap::new());
+
+/// Register a namespace, `access` lists the domains allowed to open it. Return false if
+/// the name is already used.
+pub fn register_namespace(name: &str, access: &[(u64, Access)]) -> bool {
+    storage::init_data_allocator(DOMAIN_DATA_ALLOCATOR);
+    let mut namespaces = NAMESPACES.lock();
+    if namespaces.contains_key(name) {
+        return false;
+    }
+    let namespace = Namespace {
+        data: DomainDataMap::new(),
+        access: Arc::new(Mutex::new(access.iter().copied().collect())),
+    };
+    namespaces.insert(name.to_string(), namespace);
+    log::info!("register storage namespace: {}", name);
+    true
+}
+
+/// Grant the domain access to the namespace, or revoke it with `None`. The change applies
+/// to the handles the domain has already opened.
+pub fn set_namespace_access(name: &str, domain_id: u64, access: Option<Access>) -> bool {
+    let namespaces = NAMESPACES.lock();
+    let namespace = match namespaces.get(name) {
+        Some(namespace) => namespace,
+        None => return false,
+    };
+    let mut domains = namespace.access.lock();
+    match access {
+        Some(access) => domains.insert(domain_id, access),
+        None => domains.remove(&domain_id),
+    };
+    true
+}
+
+/// Remove the namespace, the handles still opened by domains are denied from then on.
+pub fn remove_namespace(name: &str) -> bool {
+    let namespace = NAMESPACES.lock().remove(name);
+    match namespace {
+        Some(namespace) => {
+            namespace.access.lock().clear();
+            log::info!("remove storage namespace: {}", name);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Give the access of the source domain to the target domain which replaces it.
+pub(crate) fn move_namespace_access(from: u64, to: u64) {
+    let namespaces = NAMESPACES.lock();
+    for namespace in namespaces.values() {
+        let mut domains = namespace.access.lock();
+        if let Some(access) = domains.remove(&from) {
+            domains.insert(to, access);
+        }
+    }
+}
+
+/// Open the namespace for the domain in `owner`, which must be allowed to read it.
+pub(crate) fn open_namespace(name: &str, owner: &Arc<AtomicU64>) -> StorageResult<SharedStorage> {
+    let namespace = NAMESPACES
+        .lock()
+        .get(name)
+        .cloned()
+        .ok_or(StorageError::NotFound)?;
+    let handle = NamespaceHandle {
+        namespace,
+        owner: owner.clone(),
+    };
+    handle.check(Access::Read)?;
+    Ok(Arc::new_in(handle, CustomStorge))
+}
+
+/// A namespace opened by a domain.
+///
+/// The access is checked on every operation against the current domain id, so it follows
+/// the domain when it is replaced and a revoked access takes effect at once.
+struct NamespaceHandle {
+    namespace: Namespace,
+    owner: Arc<AtomicU64>,
+}
+
+impl NamespaceHandle {
+    fn check(&self, need: Access) -> StorageResult<()> {
+        let domain_id = self.owner.load(Ordering::Relaxed);
+        match (self.namespace.access.lock().get(&domain_id), need) {
+            (Some(Access::ReadWrite), _) | (Some(Access::Read), Access::Read) => Ok(()),
+            _ => Err(StorageError::PermissionDenied),
+        }
+    }
+}
+
+impl DomainDataStorage for NamespaceHandle {
+    fn insert(&self, key: &str, value: StorageEntry) -> StorageResult<Option<StorageEntry>> {
+        self.check(Access::ReadWrite)?;
+        self.namespace.data.insert(key, value)
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Option<StorageEntry>> {
+        self.check(Access::Read)?;
+        self.namespace.data.get(key)
+    }
+
+    fn remove(&self, key: &str) -> StorageResult<Option<StorageEntry>> {
+        self.check(Access::ReadWrite)?;
+        self.namespace.data.remove(key)
+    }
+
+    fn remove_unique(&self, key: &str, tag: TypeTag) -> StorageResult<Option<StorageEntry>> {
+        self.check(Access::ReadWrite)?;
+        self.namespace.data.remove_unique(key, tag)
+    }
+
+    /// A transaction of one domain would hide the changes of the others, so it is not
+    /// supported in a shared namespace.
+    fn begin(&self) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn commit(&self) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn abort(&self) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn in_transaction(&self) -> bool {
+        false
+    }
+
+    fn open_namespace(&self, name: &str) -> StorageResult<SharedStorage> {
+        open_namespace(name, &self.owner)
+    }
+}
@@ -0,0 +1,301 @@
+//! Write the persistent values of the domain storage to a device and restore them at boot.
+//!
+//! The region of the device is split into two slots which are written alternately, so a
+//! crash while saving keeps the previous image. A slot holds an image header followed by
+//! the records, and the newest slot whose checksum matches is restored. Domain ids change
+//! across reboots, so the records are keyed by the name of the domain.
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use spin::Mutex;
+use storage::{
+    CustomStorge, DomainDataStorage, PersistDevice, Persisted, StorageEntry, StorageError,
+    StorageResult,
+};
+
+use crate::storage_heap::{DomainDataMap, DATA_BASE_MANAGER, DOMAIN_DATA_ALLOCATOR};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"DOMSTORE");
+const FORMAT_VERSION: u32 = 1;
+/// magic, format version, number of records, generation, payload length, checksum
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8 + 8 + 8;
+/// name length, key length, value version, value length
+const RECORD_HEADER_SIZE: usize = 2 + 2 + 4 + 4;
+
+/// The databases restored for domains which have not been created yet.
+static RESTORED: Mutex<BTreeMap<String, DomainDataMap>> = Mutex::new(BTreeMap::new());
+
+struct ImageHeader {
+    records: u32,
+    generation: u64,
+    len: u64,
+    checksum: u64,
+}
+
+impl ImageHeader {
+    fn write(&self, buf: &mut [u8]) {
+        let mut writer = Writer { buf, pos: 0 };
+        writer.put(&MAGIC.to_le_bytes());
+        writer.put(&FORMAT_VERSION.to_le_bytes());
+        writer.put(&self.records.to_le_bytes());
+        writer.put(&self.generation.to_le_bytes());
+        writer.put(&self.len.to_le_bytes());
+        writer.put(&self.checksum.to_le_bytes());
+    }
+
+    /// Read the header of a slot, `None` if the slot has never been written.
+    fn read(buf: &[u8]) -> StorageResult<Option<Self>> {
+        let mut reader = Reader { buf, pos: 0 };
+        if reader.u64()? != MAGIC {
+            return Ok(None);
+        }
+        if reader.u32()? != FORMAT_VERSION {
+            return Err(StorageError::Corrupted);
+        }
+        Ok(Some(Self {
+            records: reader.u32()?,
+            generation: reader.u64()?,
+            len: reader.u64()?,
+            checksum: reader.u64()?,
+        }))
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> StorageResult<&'a [u8]> {
+        let data = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(StorageError::Corrupted)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> StorageResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> StorageResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> StorageResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self, len: usize) -> StorageResult<&'a str> {
+        core::str::from_utf8(self.take(len)?).map_err(|_| StorageError::Corrupted)
+    }
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn slot_size(device: &dyn PersistDevice) -> usize {
+    device.capacity() / 2
+}
+
+/// Read the headers of both slots.
+fn read_headers(device: &dyn PersistDevice) -> StorageResult<[Option<ImageHeader>; 2]> {
+    let mut headers = [None, None];
+    for (slot, header) in headers.iter_mut().enumerate() {
+        let mut buf = [0; HEADER_SIZE];
+        device.read(slot * slot_size(device), &mut buf)?;
+        // a damaged header is treated as an empty slot, the other one is still valid
+        *header = ImageHeader::read(&buf).unwrap_or(None);
+    }
+    Ok(headers)
+}
+
+/// Append a record to the image, the value is written by the entry itself.
+fn put_record(
+    buf: &mut [u8],
+    pos: usize,
+    name: &str,
+    key: &str,
+    entry: &StorageEntry,
+) -> StorageResult<Option<usize>> {
+    let version = match entry.persist_version() {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let (name_len, key_len) = match (u16::try_from(name.len()), u16::try_from(key.len())) {
+        (Ok(name_len), Ok(key_len)) => (name_len, key_len),
+        _ => return Err(StorageError::TooLong),
+    };
+    let data_pos = pos + RECORD_HEADER_SIZE + name.len() + key.len();
+    let len = buf
+        .get_mut(data_pos..)
+        .and_then(|data| entry.save(data))
+        .ok_or(StorageError::NoSpace)?;
+    let mut writer = Writer { buf, pos };
+    writer.put(&name_len.to_le_bytes());
+    writer.put(&key_len.to_le_bytes());
+    writer.put(&version.to_le_bytes());
+    writer.put(&(len as u32).to_le_bytes());
+    writer.put(name.as_bytes());
+    writer.put(key.as_bytes());
+    Ok(Some(data_pos + len))
+}
+
+/// Write the committed persistent values of every domain to the device, `name_of` returns
+/// the name of a domain. Return the number of values written.
+pub fn save_domain_databases<F>(device: &dyn PersistDevice, name_of: F) -> StorageResult<usize>
+where
+    F: Fn(u64) -> Option<String>,
+{
+    let headers = read_headers(device)?;
+    let (slot, generation) = match &headers {
+        [Some(a), Some(b)] if a.generation >= b.generation => (1, a.generation + 1),
+        [Some(_), Some(b)] => (0, b.generation + 1),
+        [Some(a), None] => (1, a.generation + 1),
+        [None, Some(b)] => (0, b.generation + 1),
+        [None, None] => (0, 1),
+    };
+    let mut buf = vec![0; slot_size(device)];
+    let mut pos = HEADER_SIZE;
+    let mut records = 0;
+    // the values are saved by the code of their domains, no lock is held meanwhile
+    let databases = DATA_BASE_MANAGER
+        .lock()
+        .databases()
+        .map(|(domain_id, map)| (domain_id, map.clone()))
+        .collect::<Vec<_>>();
+    // the values of domains which have not been started since the boot
+    let restored = RESTORED
+        .lock()
+        .iter()
+        .map(|(name, map)| (name.clone(), map.clone()))
+        .collect::<Vec<_>>();
+    let databases = databases
+        .into_iter()
+        .filter_map(|(domain_id, map)| Some((name_of(domain_id)?, map)))
+        .chain(restored);
+    for (name, map) in databases {
+        for (key, entry) in map.committed() {
+            if let Some(end) = put_record(&mut buf, pos, &name, &key, &entry)? {
+                pos = end;
+                records += 1;
+            }
+        }
+    }
+    let header = ImageHeader {
+        records,
+        generation,
+        len: (pos - HEADER_SIZE) as u64,
+        checksum: checksum(&buf[HEADER_SIZE..pos]),
+    };
+    header.write(&mut buf);
+    device.write(slot * slot_size(device), &buf[..pos])?;
+    device.flush()?;
+    log::info!(
+        "save {} persistent values, generation: {}",
+        records,
+        generation
+    );
+    Ok(records as usize)
+}
+
+/// Read the newest image from the device. The values are attached to the domains by
+/// [`attach_restored_database`] when they are created. Return the number of values read.
+pub fn restore_domain_databases(device: &dyn PersistDevice) -> StorageResult<usize> {
+    storage::init_data_allocator(DOMAIN_DATA_ALLOCATOR);
+    let headers = read_headers(device)?;
+    let mut slots = headers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(slot, header)| Some((slot, header?)))
+        .collect::<Vec<_>>();
+    if slots.is_empty() {
+        return Ok(0);
+    }
+    slots.sort_by_key(|(_, header)| core::cmp::Reverse(header.generation));
+    for (slot, header) in slots {
+        match restore_slot(device, slot, &header) {
+            Ok(records) => return Ok(records),
+            Err(err) => log::warn!("persistent storage slot {} is damaged: {:?}", slot, err),
+        }
+    }
+    Err(StorageError::Corrupted)
+}
+
+fn restore_slot(
+    device: &dyn PersistDevice,
+    slot: usize,
+    header: &ImageHeader,
+) -> StorageResult<usize> {
+    let len = header.len as usize;
+    if HEADER_SIZE + len > slot_size(device) {
+        return Err(StorageError::Corrupted);
+    }
+    let mut buf = vec![0; len];
+    device.read(slot * slot_size(device) + HEADER_SIZE, &mut buf)?;
+    if checksum(&buf) != header.checksum {
+        return Err(StorageError::Corrupted);
+    }
+    let mut databases = BTreeMap::<String, DomainDataMap>::new();
+    let mut reader = Reader { buf: &buf, pos: 0 };
+    for _ in 0..header.records {
+        let name_len = reader.u16()? as usize;
+        let key_len = reader.u16()? as usize;
+        let version = reader.u32()?;
+        let data_len = reader.u32()? as usize;
+        let name = reader.str(name_len)?;
+        let key = reader.str(key_len)?;
+        // a damaged length fails here instead of allocating
+        let bytes = reader.take(data_len)?;
+        let mut data = Vec::with_capacity_in(data_len, CustomStorge);
+        data.extend_from_slice(bytes);
+        databases
+            .entry(name.to_string())
+            .or_insert_with(DomainDataMap::new)
+            .insert(key, StorageEntry::restored(Persisted { version, data }))?;
+    }
+    log::info!(
+        "restore {} persistent values, generation: {}",
+        header.records,
+        header.generation
+    );
+    *RESTORED.lock() = databases;
+    Ok(header.records as usize)
+}
+
+/// Give the restored database of the domain named `name` to the domain, it must be called
+/// before the domain is initialized. Return whether there was one.
+pub fn attach_restored_database(domain_id: u64, name: &str) -> bool {
+    let map = match RESTORED.lock().remove(name) {
+        Some(map) => map,
+        None => return false,
+    };
+    DATA_BASE_MANAGER.lock().attach(domain_id, map);
+    log::info!(
+        "attach restored database of {} to domain: {}",
+        name,
+        domain_id
+    );
+    true
+}
@@ -3,14 +3,34 @@ use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use spin::Mutex;
 
 use crate::{
-    sheap::{free_domain_shared_data, FreeShared},
+    sheap::{free_domain_shared_data, released_bytes, trim, FreeShared},
     storage_heap::DomainDataMap,
     FRAME_BITS,
 };
 
 pub static DOMAIN_RESOURCE: Mutex<DomainResource> = Mutex::new(DomainResource::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PageMapError {
+    /// The domain would hold more pages than its quota.
+    QuotaExceeded { quota: usize, held: usize },
+    /// Some of the pages are not held by the domain.
+    NotHeld,
+}
+
+/// The pages held by a domain.
+#[derive(Debug, Copy, Clone)]
+pub struct PageHolding {
+    pub domain_id: u64,
+    pub pages: usize,
+    pub ranges: usize,
+    pub quota: Option<usize>,
+}
+
 pub struct DomainResource {
+    /// The ranges of pages `(first page, number)` held by each domain.
     page_map: BTreeMap<u64, Vec<(usize, usize)>>,
+    page_quota: BTreeMap<u64, usize>,
     box_data: BTreeMap<u64, usize>,
 }
 
@@ -18,23 +38,136 @@ impl DomainResource {
     pub const fn new() -> Self {
         Self {
             page_map: BTreeMap::new(),
+            page_quota: BTreeMap::new(),
             box_data: BTreeMap::new(),
         }
     }
 
-    pub fn insert_page_map(&mut self, domain_id: u64, page: (usize, usize)) {
+    /// Limit the number of pages the domain may hold, `None` removes the limit.
+    pub fn set_page_quota(&mut self, domain_id: u64, quota: Option<usize>) {
+        match quota {
+            Some(quota) => self.page_quota.insert(domain_id, quota),
+            None => self.page_quota.remove(&domain_id),
+        };
+    }
+
+    pub fn page_quota(&self, domain_id: u64) -> Option<usize> {
+        self.page_quota.get(&domain_id).copied()
+    }
+
+    /// Check that the domain may get `n` more pages, `sys_alloc_pages` calls it before
+    /// allocating them.
+    pub fn check_page_quota(&self, domain_id: u64, n: usize) -> Result<(), PageMapError> {
+        let held = self.pages(domain_id);
+        match self.page_quota(domain_id) {
+            Some(quota) if held + n > quota => Err(PageMapError::QuotaExceeded { quota, held }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record `n` pages starting at `page` as held by the domain.
+    pub fn insert_page_map(
+        &mut self,
+        domain_id: u64,
+        (page, n): (usize, usize),
+    ) -> Result<(), PageMapError> {
+        self.check_page_quota(domain_id, n)?;
         let vec = self.page_map.entry(domain_id).or_default();
-        vec.push(page);
+        vec.push((page, n));
+        Ok(())
     }
 
-    pub fn free_page_map(&mut self, domain_id: u64, page: usize) {
-        let vec = self.page_map.get_mut(&domain_id).unwrap();
-        vec.retain(|(s, _)| *s != page);
+    /// Release `n` pages starting at `page`, they may be a part of a recorded range or span
+    /// several ranges. Nothing is released unless the domain holds all of them.
+    pub fn free_page_map(
+        &mut self,
+        domain_id: u64,
+        page: usize,
+        n: usize,
+    ) -> Result<(), PageMapError> {
+        let vec = self
+            .page_map
+            .get_mut(&domain_id)
+            .ok_or(PageMapError::NotHeld)?;
+        let end = page + n;
+        let held = vec
+            .iter()
+            .map(|&(start, len)| (start + len).min(end).saturating_sub(start.max(page)))
+            .sum::<usize>();
+        if held != n {
+            return Err(PageMapError::NotHeld);
+        }
+        let mut rest = Vec::with_capacity(vec.len() + 1);
+        for &(start, len) in vec.iter() {
+            let range_end = start + len;
+            if range_end <= page || start >= end {
+                rest.push((start, len));
+                continue;
+            }
+            if start < page {
+                rest.push((start, page - start));
+            }
+            if range_end > end {
+                rest.push((end, range_end - end));
+            }
+        }
+        if rest.is_empty() {
+            self.page_map.remove(&domain_id);
+        } else {
+            *vec = rest;
+        }
+        Ok(())
+    }
+
+    /// The number of pages held by the domain.
+    pub fn pages(&self, domain_id: u64) -> usize {
+        self.page_ranges(domain_id).iter().map(|(_, n)| n).sum()
+    }
+
+    /// The ranges of pages `(first page, number)` held by the domain.
+    pub fn page_ranges(&self, domain_id: u64) -> &[(usize, usize)] {
+        self.page_map
+            .get(&domain_id)
+            .map_or(&[], |vec| vec.as_slice())
+    }
+
+    /// The pages held by every domain which holds pages or has a quota.
+    pub fn page_holdings(&self) -> Vec<PageHolding> {
+        let mut domains = self.page_map.keys().collect::<Vec<_>>();
+        domains.extend(self.page_quota.keys());
+        domains.sort();
+        domains.dedup();
+        domains
+            .into_iter()
+            .map(|&domain_id| PageHolding {
+                domain_id,
+                pages: self.pages(domain_id),
+                ranges: self.page_ranges(domain_id).len(),
+                quota: self.page_quota(domain_id),
+            })
+            .collect()
     }
 
     pub fn insert_box_data(&mut self, domain_id: u64, data: usize) {
         self.box_data.insert(domain_id, data);
     }
+
+    /// The memory the shared heap caches have returned to the kernel heap.
+    pub fn shared_heap_released(&self) -> usize {
+        released_bytes()
+    }
+}
+
+/// Return the idle memory of the shared heap caches to the kernel heap. `sys_alloc_pages`
+/// calls it when it runs short of pages, before failing the allocation.
+///
+/// Return the bytes released by this call.
+pub fn reclaim_memory() -> usize {
+    let released = trim();
+    if released != 0 {
+        log::info!("reclaim {:#x} bytes from the shared heap", released);
+    }
+    released
 }
 
 pub fn register_domain_resource(domain_id: u64, box_ptr: usize) {
@@ -55,8 +188,16 @@ where
     free_domain_shared_data(domain_id, free_shared);
 
     let mut binding = DOMAIN_RESOURCE.lock();
-    // free pages
+    binding.page_quota.remove(&domain_id);
+    // free the pages the domain never freed
     if let Some(vec) = binding.page_map.remove(&domain_id) {
+        let pages = vec.iter().map(|(_, n)| n).sum::<usize>();
+        log::warn!(
+            "[Domain: {}] leaked {} pages in {} ranges",
+            domain_id,
+            pages,
+            vec.len()
+        );
         for (page_start, n) in vec {
             let page_end = page_start + n;
             log::warn!(
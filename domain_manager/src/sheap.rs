@@ -1,108 +1,562 @@
+//! The shared heap used by domains to exchange data.
+//!
+//! Every object is preceded by an [`ObjectHeader`] which records its owner. Small objects are
+//! served from per-CPU caches of power-of-two size classes, which are refilled from a global
+//! depot and from page aligned slabs. Live objects are linked into an intrusive list of their
+//! owner, so the data of a crashed domain is found without scanning the whole heap.
+//!
+//! A depot holding more free memory than its high-water mark returns the slabs whose blocks
+//! are all free to the kernel heap, and [`trim`] returns every idle slab when pages run short.
+//!
+//! With the `debug` feature, each value is surrounded by canaries, freed memory is poisoned
+//! and the header records the type name and the domains which allocated and freed the value.
+//! The borrows of data passed by reference between domains are recorded as well, so a read
+//! of data which has been moved away is caught.
 use alloc::{
     alloc::{alloc, dealloc},
     collections::BTreeMap,
-    sync::Arc,
-    vec,
+    string::String,
     vec::Vec,
 };
-use core::{alloc::Layout, any::TypeId};
+use core::{
+    alloc::Layout,
+    any::TypeId,
+    mem::{align_of, size_of},
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use hashbrown::HashMap;
-use shared_heap::{SharedHeapAlloc, SharedHeapAllocation};
-use spin::{Lazy, Mutex};
+use config::CPU_NUM;
+use shared_heap::{
+    DVec, DomainUsage, RingWaiter, SharedHeapAlloc, SharedHeapAllocation, SharedHeapReport,
+    TypeUsage, SHARED_DOMAIN_ID,
+};
+use spin::{Mutex, Once, RwLock};
 
 use crate::FRAME_SIZE;
 
-static SHARED_HEAP: Mutex<BTreeMap<usize, SharedHeapAllocation>> = Mutex::new(BTreeMap::new());
-
 pub static SHARED_HEAP_ALLOCATOR: &'static dyn SharedHeapAlloc = &SharedHeapAllocator;
 
-struct SharedHeapAllocationPart {
-    value_pointer: *mut u8,
-    domain_id_pointer: *mut u64,
+/// The smallest size class (128 bytes), it leaves room for the header and a small value.
+const MIN_CLASS_BITS: usize = 7;
+/// The largest size class (8 KiB), larger objects are allocated from the kernel heap directly.
+const MAX_CLASS_BITS: usize = 13;
+const NUM_CLASSES: usize = MAX_CLASS_BITS - MIN_CLASS_BITS + 1;
+/// The size of the slabs used to refill a size class.
+const SLAB_SIZE: usize = 4 * FRAME_SIZE;
+/// The number of free objects a CPU keeps per size class.
+const CPU_CACHE_LIMIT: usize = 64;
+/// The number of free objects moved between a CPU cache and the depot at once.
+const CPU_CACHE_BATCH: usize = CPU_CACHE_LIMIT / 2;
+/// The free memory the depot keeps per size class before its idle slabs are returned.
+const DEPOT_HIGH_WATER: usize = 4 * SLAB_SIZE;
+
+const LARGE_CLASS: u32 = u32::MAX;
+const OBJECT_ALIVE: u32 = 0x5348_4541;
+const OBJECT_FREE: u32 = 0;
+/// The object belongs to a crashed domain and is released by the cleanup.
+const OBJECT_DYING: u32 = 0x4459_494e;
+/// The object is not linked into any domain list.
+const NO_LIST: u64 = u64::MAX;
+
+#[repr(C)]
+struct ObjectHeader {
+    /// The owner of the object, `SharedHeapAllocation::domain_id_pointer` points here.
+    domain_id: u64,
+    /// The domain whose list links the object.
+    list_domain: u64,
+    prev: *mut ObjectHeader,
+    next: *mut ObjectHeader,
+    layout: Layout,
+    type_id: TypeId,
+    drop_fn: fn(TypeId, *mut u8, Layout),
+    class: u32,
+    magic: u32,
+    /// Must be the last field, its canary is placed right before the value.
+    #[cfg(feature = "debug")]
+    debug: debug::DebugInfo,
+}
+
+const HEADER_SIZE: usize = size_of::<ObjectHeader>();
+#[cfg(feature = "debug")]
+const TRAILER_SIZE: usize = debug::CANARY_SIZE;
+#[cfg(not(feature = "debug"))]
+const TRAILER_SIZE: usize = 0;
+
+impl ObjectHeader {
+    /// Return the header of the object whose value starts at `ptr`.
+    fn from_value(ptr: *mut u8) -> *mut ObjectHeader {
+        ptr.wrapping_sub(HEADER_SIZE) as *mut ObjectHeader
+    }
+
+    fn value_pointer(&mut self) -> *mut u8 {
+        (self as *mut Self as *mut u8).wrapping_add(HEADER_SIZE)
+    }
+
+    fn block_pointer(&mut self) -> *mut u8 {
+        let (offset, _) = block_layout(&self.layout);
+        self.value_pointer().wrapping_sub(offset)
+    }
+
+    fn allocation(&mut self) -> SharedHeapAllocation {
+        SharedHeapAllocation {
+            value_pointer: self.value_pointer(),
+            domain_id_pointer: &mut self.domain_id,
+            layout: self.layout,
+            type_id: self.type_id,
+            drop_fn: self.drop_fn,
+        }
+    }
+}
+
+/// Return the offset of the value in its block and the layout of the block.
+///
+/// The header ends right where the value starts.
+fn block_layout(layout: &Layout) -> (usize, Layout) {
+    let align = layout.align().max(align_of::<ObjectHeader>());
+    let offset = HEADER_SIZE.next_multiple_of(align);
+    let block = Layout::from_size_align(offset + layout.size() + TRAILER_SIZE, align).unwrap();
+    (offset, block)
+}
+
+/// Return the size class serving the block, or `None` if it is too large.
+fn size_class(block: &Layout) -> Option<usize> {
+    let size = block.size().max(block.align()).next_power_of_two();
+    let bits = size.trailing_zeros() as usize;
+    if bits > MAX_CLASS_BITS {
+        None
+    } else {
+        Some(bits.saturating_sub(MIN_CLASS_BITS))
+    }
+}
+
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_BITS)
+}
+
+fn slab_size(class: usize) -> usize {
+    class_size(class).max(SLAB_SIZE)
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// An intrusive list of free blocks of one size class.
+struct FreeList {
+    head: *mut FreeBlock,
+    len: usize,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, block: *mut u8) {
+        let block = block as *mut FreeBlock;
+        unsafe { (*block).next = self.head };
+        self.head = block;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let block = self.head;
+        self.head = unsafe { (*block).next };
+        self.len -= 1;
+        Some(block as *mut u8)
+    }
+
+    /// Move at most `n` blocks into `other`.
+    fn move_to(&mut self, other: &mut FreeList, n: usize) {
+        for _ in 0..n {
+            match self.pop() {
+                Some(block) => other.push(block),
+                None => break,
+            }
+        }
+    }
+
+    /// Sort the blocks by address, so the blocks of a slab are adjacent.
+    fn sort(&mut self) {
+        self.head = unsafe { merge_sort(self.head) };
+    }
+
+    /// Return the slabs of the size class whose blocks are all in the list to the kernel
+    /// heap, while at least `keep` blocks stay in the list. Return the bytes released.
+    fn release_slabs(&mut self, class: usize, keep: usize) -> usize {
+        let size = slab_size(class);
+        let blocks_per_slab = size / class_size(class);
+        self.sort();
+        let mut rest = core::mem::replace(self, FreeList::new());
+        let mut released = 0;
+        while let Some(block) = rest.pop() {
+            let slab = block as usize & !(size - 1);
+            let mut blocks = FreeList::new();
+            blocks.push(block);
+            while !rest.head.is_null() && rest.head as usize & !(size - 1) == slab {
+                rest.move_to(&mut blocks, 1);
+            }
+            if blocks.len == blocks_per_slab && self.len + rest.len >= keep {
+                unsafe {
+                    dealloc(
+                        slab as *mut u8,
+                        Layout::from_size_align(size, size).unwrap(),
+                    )
+                };
+                released += size;
+            } else {
+                blocks.move_to(self, blocks.len);
+            }
+        }
+        released
+    }
+}
+
+/// Sort a list of blocks without allocating, it runs when memory is short.
+unsafe fn merge_sort(head: *mut FreeBlock) -> *mut FreeBlock {
+    if head.is_null() || (*head).next.is_null() {
+        return head;
+    }
+    let (mut slow, mut fast) = (head, (*head).next);
+    while !fast.is_null() && !(*fast).next.is_null() {
+        slow = (*slow).next;
+        fast = (*(*fast).next).next;
+    }
+    let second = (*slow).next;
+    (*slow).next = null_mut();
+    let (mut a, mut b) = (merge_sort(head), merge_sort(second));
+    let mut head = null_mut();
+    let mut tail: *mut *mut FreeBlock = &mut head;
+    while !a.is_null() && !b.is_null() {
+        let block = if (a as usize) < (b as usize) {
+            let block = a;
+            a = (*a).next;
+            block
+        } else {
+            let block = b;
+            b = (*b).next;
+            block
+        };
+        *tail = block;
+        tail = &mut (*block).next;
+    }
+    *tail = if a.is_null() { b } else { a };
+    head
+}
+
+struct CpuCache {
+    classes: [Mutex<FreeList>; NUM_CLASSES],
+}
+
+impl CpuCache {
+    const fn new() -> Self {
+        Self {
+            classes: [const { Mutex::new(FreeList::new()) }; NUM_CLASSES],
+        }
+    }
+}
+
+/// The free blocks of one size class shared by all CPUs.
+struct Depot {
+    blocks: FreeList,
+    /// The number of free blocks kept before the idle slabs are returned.
+    high_water: usize,
+    /// The number of free blocks at which the depot is shrunk next, it is raised when the
+    /// free blocks are spread over partially used slabs.
+    shrink_at: usize,
+}
+
+impl Depot {
+    const fn new(class: usize) -> Self {
+        let high_water = DEPOT_HIGH_WATER / class_size(class);
+        Self {
+            blocks: FreeList::new(),
+            high_water,
+            shrink_at: high_water,
+        }
+    }
+
+    /// Return idle slabs until the depot is back to half of its high-water mark.
+    fn shrink(&mut self, class: usize) -> usize {
+        let released = self.blocks.release_slabs(class, self.high_water / 2);
+        self.shrink_at = self.high_water.max(self.blocks.len * 2);
+        released
+    }
 }
-unsafe impl Send for SharedHeapAllocationPart {}
 
 pub struct SharedHeapCache {
-    cache: Mutex<HashMap<Layout, Arc<Mutex<Vec<SharedHeapAllocationPart>>>>>,
+    cpus: [CpuCache; CPU_NUM],
+    depot: [Mutex<Depot>; NUM_CLASSES],
 }
 
 impl SharedHeapCache {
-    fn new() -> Self {
+    const fn new() -> Self {
+        let mut depot = [const { Mutex::new(Depot::new(0)) }; NUM_CLASSES];
+        let mut class = 1;
+        while class < NUM_CLASSES {
+            depot[class] = Mutex::new(Depot::new(class));
+            class += 1;
+        }
         Self {
-            cache: Mutex::new(HashMap::new()),
+            cpus: [const { CpuCache::new() }; CPU_NUM],
+            depot,
+        }
+    }
+
+    fn local(&self, class: usize) -> &Mutex<FreeList> {
+        &self.cpus[current_cpu()].classes[class]
+    }
+
+    fn get(&self, class: usize) -> *mut u8 {
+        let mut local = self.local(class).lock();
+        if let Some(block) = local.pop() {
+            return block;
+        }
+        self.depot[class]
+            .lock()
+            .blocks
+            .move_to(&mut local, CPU_CACHE_BATCH);
+        if local.len == 0 {
+            Self::refill(class, &mut local);
         }
+        local.pop().unwrap_or(null_mut())
     }
-    fn get(&self, layout: &Layout) -> Option<SharedHeapAllocationPart> {
-        let vec = self.cache.lock().get(layout).cloned();
-        if let Some(vec) = vec {
-            let mut vec = vec.lock();
-            if let Some(part) = vec.pop() {
-                return Some(part);
+
+    fn insert(&self, class: usize, block: *mut u8) {
+        let mut local = self.local(class).lock();
+        local.push(block);
+        if local.len > CPU_CACHE_LIMIT {
+            let mut depot = self.depot[class].lock();
+            local.move_to(&mut depot.blocks, CPU_CACHE_BATCH);
+            if depot.blocks.len > depot.shrink_at {
+                let released = depot.shrink(class);
+                RELEASED_BYTES.fetch_add(released, Ordering::Relaxed);
             }
         }
-        None
     }
-    fn insert(&self, layout: Layout, part: SharedHeapAllocationPart) {
-        let mut cache = self.cache.lock();
-        let vec = cache
-            .entry(layout)
-            .or_insert_with(|| Arc::new(Mutex::new(Vec::with_capacity(16))))
-            .clone();
-        drop(cache);
-        let mut vec = vec.lock();
-        vec.push(part);
+
+    /// Move the free blocks of every CPU into the depot and return all idle slabs.
+    fn trim(&self) -> usize {
+        let mut released = 0;
+        for (class, depot) in self.depot.iter().enumerate() {
+            // a CPU cache is locked before the depot, as in `insert`
+            for cpu in self.cpus.iter() {
+                let mut local = cpu.classes[class].lock();
+                let len = local.len;
+                local.move_to(&mut depot.lock().blocks, len);
+            }
+            let mut depot = depot.lock();
+            released += depot.blocks.release_slabs(class, 0);
+            depot.shrink_at = depot.high_water.max(depot.blocks.len * 2);
+        }
+        released
+    }
+
+    /// Return the number and the total size of the free blocks in the caches.
+    fn free_blocks(&self) -> (usize, usize) {
+        let cpus = self
+            .cpus
+            .iter()
+            .flat_map(|cpu| cpu.classes.iter().enumerate())
+            .map(|(class, list)| (class, list.lock().len));
+        let depot = self
+            .depot
+            .iter()
+            .enumerate()
+            .map(|(class, depot)| (class, depot.lock().blocks.len));
+        cpus.chain(depot)
+            .fold((0, 0), |(blocks, bytes), (class, len)| {
+                (blocks + len, bytes + len * class_size(class))
+            })
+    }
+
+    /// Carve a new slab into blocks of the size class.
+    fn refill(class: usize, list: &mut FreeList) {
+        let size = slab_size(class);
+        let slab = unsafe { alloc(Layout::from_size_align(size, size).unwrap()) };
+        if slab.is_null() {
+            return;
+        }
+        let block_size = class_size(class);
+        (0..size / block_size)
+            .rev()
+            .for_each(|i| list.push(slab.wrapping_add(i * block_size)));
+    }
+}
+
+static SHARED_HEAP_CACHE: SharedHeapCache = SharedHeapCache::new();
+
+/// The memory returned by the caches to the kernel heap so far.
+static RELEASED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Return the idle slabs of all caches to the kernel heap, it is invoked when the kernel
+/// runs short of pages. Return the bytes released.
+pub fn trim() -> usize {
+    let released = SHARED_HEAP_CACHE.trim();
+    RELEASED_BYTES.fetch_add(released, Ordering::Relaxed);
+    released
+}
+
+/// The memory returned by the caches to the kernel heap, by [`trim`] and by the depots
+/// going over their high-water mark.
+pub fn released_bytes() -> usize {
+    RELEASED_BYTES.load(Ordering::Relaxed)
+}
+
+static CURRENT_CPU: Once<fn() -> usize> = Once::new();
+
+/// Set the function used to find the cache of the current CPU, and the waiter used to
+/// wake up the tasks blocked on a [`DRing`](shared_heap::DRing) whose other endpoint is
+/// dropped by the crash cleanup.
+///
+/// Until it is called, all CPUs share the first cache. The data allocated by the kernel,
+/// such as usage reports, is owned by [`SHARED_DOMAIN_ID`] until it is handed to a domain.
+pub fn init_shared_heap(current_cpu: fn() -> usize, ring_waiter: &'static dyn RingWaiter) {
+    CURRENT_CPU.call_once(|| current_cpu);
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, SHARED_DOMAIN_ID);
+    shared_heap::init_ring_waiter(ring_waiter);
+}
+
+fn current_cpu() -> usize {
+    CURRENT_CPU.get().map_or(0, |f| f()) % CPU_NUM
+}
+
+/// The live objects owned by one domain.
+struct ObjectList {
+    head: *mut ObjectHeader,
+    count: usize,
+    bytes: usize,
+}
+
+unsafe impl Send for ObjectList {}
+
+impl ObjectList {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            count: 0,
+            bytes: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, header: *mut ObjectHeader) {
+        (*header).prev = null_mut();
+        (*header).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = header;
+        }
+        self.head = header;
+        self.count += 1;
+        self.bytes += (*header).layout.size();
+    }
+
+    unsafe fn remove(&mut self, header: *mut ObjectHeader) {
+        let (prev, next) = ((*header).prev, (*header).next);
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*header).prev = null_mut();
+        (*header).next = null_mut();
+        self.count -= 1;
+        self.bytes -= (*header).layout.size();
     }
+
+    unsafe fn pop(&mut self) -> Option<*mut ObjectHeader> {
+        let header = self.head;
+        if header.is_null() {
+            return None;
+        }
+        self.remove(header);
+        Some(header)
+    }
+}
+
+static DOMAIN_OBJECTS: RwLock<BTreeMap<u64, Mutex<ObjectList>>> = RwLock::new(BTreeMap::new());
+
+fn with_list<R>(domain_id: u64, f: impl FnOnce(&mut ObjectList) -> R) -> R {
+    let lists = DOMAIN_OBJECTS.read();
+    if let Some(list) = lists.get(&domain_id) {
+        return f(&mut list.lock());
+    }
+    drop(lists);
+    let mut lists = DOMAIN_OBJECTS.write();
+    let list = lists
+        .entry(domain_id)
+        .or_insert_with(|| Mutex::new(ObjectList::new()));
+    f(list.get_mut())
+}
+
+unsafe fn link(header: *mut ObjectHeader, domain_id: u64) {
+    with_list(domain_id, |list| list.push(header));
+    (*header).list_domain = domain_id;
 }
 
-static SHARED_HEAP_CACHE: Lazy<SharedHeapCache> = Lazy::new(SharedHeapCache::new);
+unsafe fn unlink(header: *mut ObjectHeader) {
+    let domain_id = (*header).list_domain;
+    if domain_id == NO_LIST {
+        return;
+    }
+    with_list(domain_id, |list| list.remove(header));
+    (*header).list_domain = NO_LIST;
+}
+
+/// Pop one object from the list of the domain.
+fn pop_object(domain_id: u64) -> Option<*mut ObjectHeader> {
+    let lists = DOMAIN_OBJECTS.read();
+    let header = unsafe { lists.get(&domain_id)?.lock().pop()? };
+    unsafe { (*header).list_domain = NO_LIST };
+    Some(header)
+}
+
+/// The names of the types registered by domains.
+static TYPE_NAMES: Mutex<BTreeMap<TypeId, String>> = Mutex::new(BTreeMap::new());
+
 pub struct SharedHeapAllocator;
 
 impl SharedHeapAllocator {
-    fn alloc_from_cache(
-        layout: &Layout,
-        type_id: TypeId,
-        drop_fn: fn(TypeId, *mut u8),
-    ) -> Option<(*mut u8, SharedHeapAllocation)> {
-        let part = SHARED_HEAP_CACHE.get(layout);
-        if let Some(part) = part {
-            let ptr = part.value_pointer;
-            let domain_id_pointer = part.domain_id_pointer;
-            let res = SharedHeapAllocation {
-                value_pointer: ptr,
-                domain_id_pointer,
-                layout: *layout,
-                type_id,
-                drop_fn,
-            };
-            return Some((ptr, res));
+    fn object_header(ptr: *mut u8) -> *mut ObjectHeader {
+        let header = ObjectHeader::from_value(ptr);
+        let magic = unsafe { (*header).magic };
+        if magic != OBJECT_ALIVE && magic != OBJECT_DYING {
+            #[cfg(feature = "debug")]
+            if magic == OBJECT_FREE {
+                unsafe { debug::report(header, "the data has been dropped") };
+            }
+            panic!(
+                "<SharedHeap> dealloc: {:#x}, but the data has been dropped",
+                ptr as usize
+            );
+        }
+        #[cfg(feature = "debug")]
+        unsafe {
+            debug::check_canary(header)
         };
-        None
+        header
     }
 
-    unsafe fn alloc_from_heap(
-        layout: Layout,
-        type_id: TypeId,
-        drop_fn: fn(TypeId, *mut u8),
-    ) -> Option<(*mut u8, SharedHeapAllocation)> {
-        let ptr = alloc(layout);
-        if ptr.is_null() {
-            panic!("<SharedHeap> alloc layout: {:?} failed", layout);
+    unsafe fn release(header: *mut ObjectHeader) {
+        #[cfg(feature = "debug")]
+        debug::poison(header);
+        (*header).magic = OBJECT_FREE;
+        let block = (*header).block_pointer();
+        match (*header).class {
+            LARGE_CLASS => dealloc(block, block_layout(&(*header).layout).1),
+            class => SHARED_HEAP_CACHE.insert(class as usize, block),
         }
-        // log::error!(
-        //     "<SharedHeap> alloc size: {}, ptr: {:#x}",
-        //     layout.size(),
-        //     ptr as usize
-        // );
-        let domain_id_pointer = alloc(Layout::for_value(&0u64)) as *mut u64;
-        let res = SharedHeapAllocation {
-            value_pointer: ptr,
-            domain_id_pointer,
-            layout,
-            type_id,
-            drop_fn,
-        };
-        Some((ptr, res))
     }
 }
 
@@ -111,66 +565,263 @@ impl SharedHeapAlloc for SharedHeapAllocator {
         &self,
         layout: Layout,
         type_id: TypeId,
-        drop_fn: fn(TypeId, *mut u8),
+        drop_fn: fn(TypeId, *mut u8, Layout),
+        domain_id: u64,
     ) -> Option<SharedHeapAllocation> {
-        if layout.size() > FRAME_SIZE {
-            let (ptr, res) = SharedHeapAllocator::alloc_from_heap(layout, type_id, drop_fn)?;
-            let mut shared_heap = SHARED_HEAP.lock();
-            shared_heap.insert(ptr as usize, res);
-            return Some(res);
-        }
-        let mut shared_heap = SHARED_HEAP.lock();
-        let res = SharedHeapAllocator::alloc_from_cache(&layout, type_id, drop_fn);
-        let (ptr, res) = if let Some((ptr, res)) = res {
-            (ptr, res)
-        } else {
-            SharedHeapAllocator::alloc_from_heap(layout, type_id, drop_fn)?
+        let (offset, block) = block_layout(&layout);
+        let class = size_class(&block);
+        let ptr = match class {
+            Some(class) => SHARED_HEAP_CACHE.get(class),
+            None => alloc(block),
         };
-        shared_heap.insert(ptr as usize, res);
-        Some(res)
+        if ptr.is_null() {
+            panic!("<SharedHeap> alloc layout: {:?} failed", layout);
+        }
+        let header = ptr.add(offset - HEADER_SIZE) as *mut ObjectHeader;
+        header.write(ObjectHeader {
+            domain_id,
+            list_domain: NO_LIST,
+            prev: null_mut(),
+            next: null_mut(),
+            layout,
+            type_id,
+            drop_fn,
+            class: class.map_or(LARGE_CLASS, |class| class as u32),
+            magic: OBJECT_ALIVE,
+            #[cfg(feature = "debug")]
+            debug: debug::DebugInfo::new(domain_id),
+        });
+        #[cfg(feature = "debug")]
+        debug::arm(header);
+        link(header, domain_id);
+        Some((*header).allocation())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8) {
-        let mut heap = SHARED_HEAP.lock();
-        let allocation = heap.remove(&(ptr as usize));
-        drop(heap);
-        if let Some(allocation) = allocation {
-            // log::error!("<SharedHeap> dealloc: {:p}", ptr);
-            assert_eq!(allocation.value_pointer, ptr);
-            if allocation.layout.size() > FRAME_SIZE {
-                dealloc(allocation.value_pointer, allocation.layout);
-                dealloc(
-                    allocation.domain_id_pointer as *mut u8,
-                    Layout::for_value(&0u64),
-                );
-            } else {
-                let part = SharedHeapAllocationPart {
-                    value_pointer: allocation.value_pointer,
-                    domain_id_pointer: allocation.domain_id_pointer,
-                };
-                SHARED_HEAP_CACHE.insert(allocation.layout, part);
+        let header = SharedHeapAllocator::object_header(ptr);
+        if (*header).magic == OBJECT_DYING {
+            // released by `free_domain_shared_data`
+            return;
+        }
+        unlink(header);
+        SharedHeapAllocator::release(header);
+    }
+
+    unsafe fn set_type(&self, ptr: *mut u8, type_id: TypeId) {
+        let header = SharedHeapAllocator::object_header(ptr);
+        (*header).type_id = type_id;
+    }
+
+    unsafe fn is_dying(&self, ptr: *mut u8) -> bool {
+        (*SharedHeapAllocator::object_header(ptr)).magic == OBJECT_DYING
+    }
+
+    fn register_type(&self, type_id: TypeId, type_name: &str) {
+        TYPE_NAMES
+            .lock()
+            .entry(type_id)
+            .or_insert_with(|| type_name.into());
+    }
+
+    unsafe fn move_to(&self, ptr: *mut u8, new_domain_id: u64) -> u64 {
+        let header = SharedHeapAllocator::object_header(ptr);
+        let old_domain_id = (*header).domain_id;
+        if old_domain_id == new_domain_id && (*header).list_domain == new_domain_id {
+            return old_domain_id;
+        }
+        unlink(header);
+        (*header).domain_id = new_domain_id;
+        link(header, new_domain_id);
+        old_domain_id
+    }
+
+    #[allow(unused_variables)]
+    unsafe fn set_type_name(&self, ptr: *mut u8, type_name: &str) {
+        #[cfg(feature = "debug")]
+        (*SharedHeapAllocator::object_header(ptr))
+            .debug
+            .set_type_name(type_name);
+    }
+
+    unsafe fn check(&self, ptr: *mut u8) {
+        SharedHeapAllocator::object_header(ptr);
+    }
+
+    #[cfg(feature = "debug")]
+    fn lend(&self, owner: u64, borrower: u64) {
+        debug::lend(owner, borrower);
+    }
+
+    #[cfg(feature = "debug")]
+    fn give_back(&self, owner: u64, borrower: u64) {
+        debug::give_back(owner, borrower);
+    }
+
+    #[cfg(feature = "debug")]
+    fn is_lent(&self, owner: u64, borrower: u64) -> bool {
+        debug::is_lent(owner, borrower)
+    }
+}
+
+#[cfg(feature = "debug")]
+mod debug {
+    use alloc::collections::{btree_map::Entry, BTreeMap};
+
+    use spin::Mutex;
+
+    use super::{ObjectHeader, OBJECT_FREE};
+
+    pub(super) const CANARY_SIZE: usize = 8;
+    const CANARY: u64 = 0x5aa5_c3c3_5aa5_c3c3;
+    const POISON: u8 = 0xdd;
+    const TYPE_NAME_LEN: usize = 64;
+
+    #[repr(C)]
+    pub(super) struct DebugInfo {
+        type_name: [u8; TYPE_NAME_LEN],
+        type_name_len: usize,
+        alloc_domain: u64,
+        free_domain: u64,
+        /// Must be the last field, it is the canary in front of the value.
+        canary: u64,
+    }
+
+    impl DebugInfo {
+        pub(super) const fn new(domain_id: u64) -> Self {
+            Self {
+                type_name: [0; TYPE_NAME_LEN],
+                type_name_len: 0,
+                alloc_domain: domain_id,
+                free_domain: u64::MAX,
+                canary: CANARY,
             }
-        } else {
-            panic!(
-                "<SharedHeap> dealloc: {:#x}, but the data has been dropped",
-                ptr as usize
-            );
+        }
+
+        /// Copy the type name, the name of the allocating domain may be unloaded later.
+        pub(super) fn set_type_name(&mut self, type_name: &str) {
+            let mut len = type_name.len().min(TYPE_NAME_LEN);
+            while !type_name.is_char_boundary(len) {
+                len -= 1;
+            }
+            self.type_name[..len].copy_from_slice(&type_name.as_bytes()[..len]);
+            self.type_name_len = len;
+        }
+
+        fn type_name(&self) -> &str {
+            core::str::from_utf8(&self.type_name[..self.type_name_len]).unwrap_or("<unknown>")
         }
     }
+
+    /// The number of borrows of the data of each owner by each borrower, recorded by the
+    /// proxies for the arguments passed by reference.
+    static LENT: Mutex<BTreeMap<(u64, u64), usize>> = Mutex::new(BTreeMap::new());
+
+    pub(super) fn lend(owner: u64, borrower: u64) {
+        *LENT.lock().entry((owner, borrower)).or_default() += 1;
+    }
+
+    pub(super) fn give_back(owner: u64, borrower: u64) {
+        if let Entry::Occupied(mut entry) = LENT.lock().entry((owner, borrower)) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    pub(super) fn is_lent(owner: u64, borrower: u64) -> bool {
+        LENT.lock().contains_key(&(owner, borrower))
+    }
+
+    unsafe fn back_canary(header: *mut ObjectHeader) -> *mut u64 {
+        let size = (*header).layout.size();
+        (*header).value_pointer().add(size) as *mut u64
+    }
+
+    /// Write the canary behind the value.
+    pub(super) unsafe fn arm(header: *mut ObjectHeader) {
+        back_canary(header).write_unaligned(CANARY);
+    }
+
+    pub(super) unsafe fn check_canary(header: *mut ObjectHeader) {
+        if (*header).debug.canary != CANARY {
+            report(header, "the canary in front of the value is corrupted");
+        }
+        if back_canary(header).read_unaligned() != CANARY {
+            report(header, "the canary behind the value is corrupted");
+        }
+    }
+
+    /// Fill the value and its canary with the poison and record which domain freed it.
+    pub(super) unsafe fn poison(header: *mut ObjectHeader) {
+        let size = (*header).layout.size() + CANARY_SIZE;
+        (*header).value_pointer().write_bytes(POISON, size);
+        (*header).debug.free_domain = (*header).domain_id;
+    }
+
+    pub(super) unsafe fn report(header: *mut ObjectHeader, reason: &str) -> ! {
+        let info = &(*header).debug;
+        let freed = (*header).magic == OBJECT_FREE;
+        panic!(
+            "<SharedHeap> {:#x}: {}, type: {}, size: {}, allocated by domain: {}, owner: {:?}, freed by domain: {:?}",
+            (*header).value_pointer() as usize,
+            reason,
+            info.type_name(),
+            (*header).layout.size(),
+            info.alloc_domain,
+            (!freed).then_some((*header).domain_id),
+            freed.then_some(info.free_domain),
+        );
+    }
 }
 
-pub fn checkout_shared_data() {
-    let heap = SHARED_HEAP.lock();
-    let mut map = BTreeMap::new();
-    heap.iter().for_each(|(_, v)| {
-        let id = v.domain_id();
-        let count = map.get(&id).unwrap_or(&0) + 1;
-        map.insert(id, count);
-    });
-    for (id, count) in map {
-        log::info!("domain_id: {}, count: {}", id, count);
+/// Report the live objects of every domain and the free memory kept in the caches.
+///
+/// The report is allocated in the shared heap and owned by [`SHARED_DOMAIN_ID`], the
+/// caller moves it to the domain asking for it.
+pub fn checkout_shared_data() -> SharedHeapReport {
+    let mut domains = Vec::new();
+    {
+        let lists = DOMAIN_OBJECTS.read();
+        let names = TYPE_NAMES.lock();
+        for (id, list) in lists.iter() {
+            let list = list.lock();
+            let mut types = BTreeMap::<TypeId, (usize, usize)>::new();
+            let mut header = list.head;
+            while !header.is_null() {
+                unsafe {
+                    let usage = types.entry((*header).type_id).or_default();
+                    usage.0 += 1;
+                    usage.1 += (*header).layout.size();
+                    header = (*header).next;
+                }
+            }
+            let types = types
+                .into_iter()
+                .map(|(type_id, (objects, bytes))| {
+                    let type_name = names.get(&type_id).map_or("", |name| name.as_str());
+                    TypeUsage::new(type_id, type_name, objects, bytes)
+                })
+                .collect::<Vec<_>>();
+            domains.push((*id, list.count, list.bytes, types));
+        }
+    }
+    // allocating the report takes the locks above
+    let mut usages = DVec::<DomainUsage>::new_uninit(domains.len());
+    for (usage, (domain_id, objects, bytes, types)) in usages.iter_mut().zip(domains) {
+        usage.write(DomainUsage {
+            domain_id,
+            objects,
+            bytes,
+            types: DVec::from_slice(&types),
+        });
+    }
+    let (free_cache_blocks, free_cache_bytes) = SHARED_HEAP_CACHE.free_blocks();
+    SharedHeapReport {
+        domains: unsafe { usages.assume_init() },
+        free_cache_blocks,
+        free_cache_bytes,
     }
-    log::info!("<checkout_shared_data> shared heap size: {}", heap.len());
 }
 
 pub enum FreeShared {
@@ -179,34 +830,46 @@ pub enum FreeShared {
 }
 
 pub fn free_domain_shared_data(id: u64, free_shared: FreeShared) {
-    // checkout_shared_data();
-    let mut data = vec![];
-    let heap = SHARED_HEAP.lock();
-    // println_color!(
-    //     34,
-    //     "<free_domain_shared_data> shared heap size: {}",
-    //     heap.len()
-    // );
-    heap.iter().for_each(|(_, v)| {
-        if v.domain_id() == id {
-            data.push(*v);
-        }
-    });
-    drop(heap);
-    // println_color!(34, "<free_domain_shared_data> for domain_id: {}", id);
-    // println_color!(34, "domain has {} data", data.len());
-
-    match free_shared {
-        FreeShared::Free => {
-            // println_color!(34, "free_shared is Free, free {} data", data.len());
-            data.into_iter().for_each(|v| unsafe {
-                v.drop_fn();
-                SharedHeapAllocator.dealloc(v.value_pointer);
-            });
-        }
-        FreeShared::NotFree(domain_id) => {
-            // println_color!(34, "free_shared is NotFree, do not free data");
-            data.into_iter().for_each(|v| v.set_domain_id(domain_id));
+    if let FreeShared::NotFree(domain_id) = free_shared {
+        if domain_id == id {
+            return;
         }
     }
+    let mut dying = Vec::new();
+    while let Some(header) = pop_object(id) {
+        unsafe {
+            let owner = (*header).domain_id;
+            if owner != id {
+                // the owner was changed without moving the object to its list
+                link(header, owner);
+                continue;
+            }
+            match free_shared {
+                FreeShared::Free => {
+                    (*header).magic = OBJECT_DYING;
+                    dying.push(header);
+                }
+                FreeShared::NotFree(domain_id) => {
+                    (*header).domain_id = domain_id;
+                    link(header, domain_id);
+                }
+            }
+        }
+    }
+    // Drop the objects after all of them are detached. A dropped object frees the
+    // data it owns, except the objects of this domain which are dropped and released
+    // here once, however many of them own each other.
+    dying
+        .iter()
+        .for_each(|header| unsafe { (**header).allocation().drop_fn() });
+    dying
+        .into_iter()
+        .for_each(|header| unsafe { SharedHeapAllocator::release(header) });
+    let mut lists = DOMAIN_OBJECTS.write();
+    if lists
+        .get_mut(&id)
+        .is_some_and(|list| list.get_mut().count == 0)
+    {
+        lists.remove(&id);
+    }
 }
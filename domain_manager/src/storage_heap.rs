@@ -3,15 +3,21 @@ use alloc::{
     collections::BTreeMap,
     string::{String, ToString},
     sync::Arc,
+    vec::Vec,
 };
 use core::{
     alloc::{AllocError, Allocator, GlobalAlloc, Layout},
-    any::Any,
     ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use spin::Mutex;
-use storage::{CustomStorge, DomainDataStorage, SendAllocator};
+use storage::{
+    DomainDataStorage, SendAllocator, SharedStorage, StorageEntry, StorageError, StorageResult,
+    TypeTag,
+};
+
+use crate::namespace::{move_namespace_access, open_namespace};
 
 pub struct DomainDataMapManager {
     map_per_domain: BTreeMap<u64, DomainDataMap>,
@@ -27,6 +33,7 @@ impl DomainDataMapManager {
     /// Create a new domain data map with the given domain id.
     fn create(&mut self, domain_id: u64) -> Option<DomainDataMap> {
         let map = DomainDataMap::new();
+        map.set_owner(domain_id);
         self.map_per_domain.insert(domain_id, map)
     }
 
@@ -40,26 +47,89 @@ impl DomainDataMapManager {
         self.map_per_domain.remove(&domain_id)
     }
 
+    /// Replace the domain data map of the domain.
+    pub(crate) fn attach(&mut self, domain_id: u64, map: DomainDataMap) {
+        map.set_owner(domain_id);
+        self.map_per_domain.insert(domain_id, map);
+    }
+
+    pub(crate) fn databases(&self) -> impl Iterator<Item = (u64, &DomainDataMap)> {
+        self.map_per_domain.iter().map(|(id, map)| (*id, map))
+    }
+
     /// Move the domain data map from the source domain to the target domain.
+    ///
+    /// The changes of a transaction left open by the source domain are discarded, the
+    /// target domain sees the last committed data.
     fn move_domain(&mut self, from: u64, to: u64) {
         if let Some(data) = self.remove(from) {
             // println_color!(32, "move domain database, it's length: {}", data.len());
+            if data.abort().is_ok() {
+                log::warn!("discard the open transaction of domain: {}", from);
+            }
+            data.set_owner(to);
             self.map_per_domain.insert(to, data);
         }
     }
 }
 
-type ArcValueType = Arc<dyn Any + Send + Sync, CustomStorge>;
+#[derive(Debug, Default)]
+struct DataTable {
+    committed: BTreeMap<String, StorageEntry>,
+    /// The changes of the open transaction, `None` marks a removed key.
+    pending: Option<BTreeMap<String, Option<StorageEntry>>>,
+}
+
+impl DataTable {
+    fn get(&self, key: &str) -> Option<&StorageEntry> {
+        match self.pending.as_ref().and_then(|pending| pending.get(key)) {
+            Some(entry) => entry.as_ref(),
+            None => self.committed.get(key),
+        }
+    }
+
+    /// The number of references to the entry held by the table.
+    fn held(&self, key: &str, entry: &StorageEntry) -> usize {
+        let committed = self.committed.get(key);
+        let pending = self
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.get(key)?.as_ref());
+        [committed, pending]
+            .into_iter()
+            .flatten()
+            .filter(|held| held.ptr_eq(entry))
+            .count()
+    }
+
+    fn set(&mut self, key: &str, value: Option<StorageEntry>) -> Option<StorageEntry> {
+        let old = self.get(key).cloned();
+        match (&mut self.pending, value) {
+            (Some(pending), value) => {
+                pending.insert(key.to_string(), value);
+                old
+            }
+            (None, Some(value)) => self.committed.insert(key.to_string(), value),
+            (None, None) => self.committed.remove(key),
+        }
+    }
+}
+
+/// The map is not owned by a domain, such as the map of a shared namespace.
+const NO_OWNER: u64 = u64::MAX;
 
 #[derive(Debug)]
 pub struct DomainDataMap {
-    data: Arc<Mutex<BTreeMap<String, ArcValueType>>>,
+    data: Arc<Mutex<DataTable>>,
+    /// The domain using the map, it opens shared namespaces with its access.
+    owner: Arc<AtomicU64>,
 }
 
 impl Clone for DomainDataMap {
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
+            owner: self.owner.clone(),
         }
     }
 }
@@ -70,12 +140,28 @@ unsafe impl Sync for DomainDataMap {}
 impl DomainDataMap {
     pub fn new() -> Self {
         Self {
-            data: Arc::new(Mutex::new(BTreeMap::new())),
+            data: Arc::new(Mutex::new(DataTable::default())),
+            owner: Arc::new(AtomicU64::new(NO_OWNER)),
         }
     }
 
+    fn set_owner(&self, domain_id: u64) {
+        self.owner.store(domain_id, Ordering::Relaxed);
+    }
+
+    /// The number of committed values.
     pub fn len(&self) -> usize {
-        self.data.lock().len()
+        self.data.lock().committed.len()
+    }
+
+    /// The committed values, taken out of the map so that no lock is held while they are
+    /// used.
+    pub(crate) fn committed(&self) -> Vec<(String, StorageEntry)> {
+        let data = self.data.lock();
+        data.committed
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
     }
 }
 impl DomainDataStorage for DomainDataMap {
@@ -83,29 +169,90 @@ impl DomainDataStorage for DomainDataMap {
     ///
     /// If the key already exists, the value will be replaced and returned.
     /// Otherwise, `None` will be returned.
-    fn insert(&self, key: &str, value: ArcValueType) -> Option<ArcValueType> {
+    fn insert(&self, key: &str, value: StorageEntry) -> StorageResult<Option<StorageEntry>> {
         // println_color!(32, "insert key: {}", key);
-        self.data.lock().insert(key.to_string(), value)
+        Ok(self.data.lock().set(key, Some(value)))
     }
 
     /// Get the value with the given key.
-    fn get(&self, key: &str) -> Option<ArcValueType> {
+    fn get(&self, key: &str) -> StorageResult<Option<StorageEntry>> {
         let data = self.data.lock();
         let v = data.get(key);
         // println_color!(32, "get key: {}, value: {:?}", key, v.is_some());
 
-        v.cloned()
+        Ok(v.cloned())
     }
 
     /// Remove the value with the given key.
     ///
     /// If the key exists, the value will be removed and returned.
     /// Otherwise, `None` will be returned.
-    fn remove(&self, key: &str) -> Option<ArcValueType> {
+    fn remove(&self, key: &str) -> StorageResult<Option<StorageEntry>> {
         let mut data = self.data.lock();
 
         // println_color!(31, "remove key: {}", key);
-        data.remove(key)
+        Ok(data.set(key, None))
+    }
+
+    /// Remove the value only if no clone of it is alive outside of the map. The lock is
+    /// held while counting, so no new clone can be taken from the map meanwhile.
+    fn remove_unique(&self, key: &str, tag: TypeTag) -> StorageResult<Option<StorageEntry>> {
+        let mut data = self.data.lock();
+        let entry = match data.get(key) {
+            Some(entry) => entry.clone(),
+            None => return Ok(None),
+        };
+        if entry.tag() != tag {
+            return Err(StorageError::TypeMismatch {
+                expected: tag,
+                found: entry.tag(),
+            });
+        }
+        let refs = entry.strong_count() - 1 - data.held(key, &entry);
+        if refs != 0 {
+            return Err(StorageError::InUse { refs });
+        }
+        data.set(key, None);
+        Ok(Some(entry))
+    }
+
+    fn begin(&self) -> StorageResult<()> {
+        let mut data = self.data.lock();
+        if data.pending.is_some() {
+            return Err(StorageError::TransactionActive);
+        }
+        data.pending = Some(BTreeMap::new());
+        Ok(())
+    }
+
+    /// Apply the changes of the transaction at once.
+    fn commit(&self) -> StorageResult<()> {
+        let mut data = self.data.lock();
+        let pending = data.pending.take().ok_or(StorageError::NoTransaction)?;
+        for (key, value) in pending {
+            match value {
+                Some(value) => data.committed.insert(key, value),
+                None => data.committed.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn abort(&self) -> StorageResult<()> {
+        // the values are dropped after the lock is released
+        let pending = self.data.lock().pending.take();
+        match pending {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NoTransaction),
+        }
+    }
+
+    fn in_transaction(&self) -> bool {
+        self.data.lock().pending.is_some()
+    }
+
+    fn open_namespace(&self, name: &str) -> StorageResult<SharedStorage> {
+        open_namespace(name, &self.owner)
     }
 }
 
@@ -169,7 +316,8 @@ unsafe impl Allocator for DomainDataHeap {
 impl SendAllocator for DomainDataHeap {}
 
 pub static DOMAIN_DATA_ALLOCATOR: &'static dyn SendAllocator = &DomainDataHeap;
-static DATA_BASE_MANAGER: Mutex<DomainDataMapManager> = Mutex::new(DomainDataMapManager::new());
+pub(crate) static DATA_BASE_MANAGER: Mutex<DomainDataMapManager> =
+    Mutex::new(DomainDataMapManager::new());
 
 /// Create a new domain data map with the given domain id.
 pub fn create_domain_database(domain_id: u64) {
@@ -198,5 +346,6 @@ pub fn remove_domain_database(domain_id: u64) -> Option<Box<DomainDataMap>> {
 pub fn move_domain_database(from: u64, to: u64) {
     let mut manager = DATA_BASE_MANAGER.lock();
     manager.move_domain(from, to);
+    move_namespace_access(from, to);
     // println_color!(32, "move domain database from {} to {}", from, to);
 }
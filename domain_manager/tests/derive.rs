@@ -0,0 +1,106 @@
+#![feature(min_specialization)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use shared_heap::{CustomDrop, DBox, DVec, Lend, RRefable, SharedData};
+
+const GENERIC: usize = 0;
+const ENUM: usize = 1;
+
+/// The drops counted by each test.
+static DROPS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Counts its drops in `DROPS[self.0]`.
+#[derive(Clone)]
+struct Counted(usize);
+
+impl CustomDrop for Counted {
+    fn custom_drop(&mut self) {
+        DROPS[self.0].fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(CustomDrop, SharedData)]
+struct Pair<T: RRefable + 'static> {
+    first: DBox<T>,
+    second: T,
+}
+
+#[derive(CustomDrop, SharedData)]
+enum Message {
+    Empty,
+    Boxed(DBox<Counted>),
+    Named { data: DVec<Counted>, len: usize },
+}
+
+#[derive(CustomDrop, SharedData)]
+struct Node {
+    value: DBox<u64>,
+    next: Option<DBox<Node>>,
+}
+
+#[test]
+fn generic_struct() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let pair = Pair {
+        first: DBox::new(Counted(GENERIC)),
+        second: Counted(GENERIC),
+    };
+    assert_eq!(pair.move_to(10), 1);
+    assert_eq!(pair.first.domain_id(), 10);
+    let mut pair = pair;
+    pair.custom_drop();
+    assert_eq!(DROPS[GENERIC].load(Ordering::SeqCst), 2);
+    core::mem::forget(pair);
+}
+
+#[test]
+fn enum_with_dbox() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    assert_eq!(Message::Empty.move_to(11), 0);
+    let boxed = Message::Boxed(DBox::new(Counted(ENUM)));
+    let named = Message::Named {
+        data: DVec::new(Counted(ENUM), 3),
+        len: 3,
+    };
+    assert_eq!(boxed.move_to(11), 1);
+    assert_eq!(named.move_to(11), 1);
+    match (&boxed, &named) {
+        (Message::Boxed(dbox), Message::Named { data, .. }) => {
+            assert_eq!(dbox.domain_id(), 11);
+            assert_eq!(data.move_to(11), 11);
+        }
+        _ => unreachable!(),
+    }
+    // the domain crashed, its data is dropped with the derived `custom_drop`
+    free_domain_shared_data(11, FreeShared::Free);
+    core::mem::forget(boxed);
+    core::mem::forget(named);
+    assert_eq!(DROPS[ENUM].load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn move_nested_dbox() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let tail = DBox::new(Node {
+        value: DBox::new(2),
+        next: None,
+    });
+    let head = DBox::new(Node {
+        value: DBox::new(1),
+        next: Some(tail),
+    });
+    assert_eq!(head.move_to(12), 1);
+    {
+        // the new owner lends the data back to be inspected
+        let _lend = Lend::new(&head, 1);
+        assert_eq!(head.domain_id(), 12);
+        assert_eq!(head.value.domain_id(), 12);
+        let tail = head.next.as_ref().unwrap();
+        assert_eq!(tail.domain_id(), 12);
+        assert_eq!(tail.value.domain_id(), 12);
+    }
+    free_domain_shared_data(12, FreeShared::Free);
+    core::mem::forget(head);
+}
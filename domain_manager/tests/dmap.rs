@@ -0,0 +1,140 @@
+#![feature(min_specialization)]
+
+use std::{
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use shared_heap::{CustomDrop, DMap, Lend, SharedData};
+
+/// Keys of the same group have the same hash, so they probe the same buckets.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Colliding {
+    group: u64,
+    id: u64,
+}
+
+impl Hash for Colliding {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.group.hash(state);
+    }
+}
+
+fn key(group: u64, id: u64) -> Colliding {
+    Colliding { group, id }
+}
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+struct Counted;
+
+impl CustomDrop for Counted {
+    fn custom_drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn remove_shifts_back_colliding_entries() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let mut map = DMap::new();
+    for id in 0..4 {
+        map.insert(key(0, id), id);
+    }
+    map.insert(key(1, 0), 10);
+    assert_eq!(map.remove(&key(0, 1)), Some(1));
+    // the entries behind the removed one are still found
+    for id in [0, 2, 3] {
+        assert_eq!(map.get(&key(0, id)), Some(&id));
+    }
+    assert_eq!(map.get(&key(1, 0)), Some(&10));
+    assert_eq!(map.get(&key(0, 1)), None);
+    assert_eq!(map.remove(&key(0, 0)), Some(0));
+    assert_eq!(map.remove(&key(0, 3)), Some(3));
+    assert_eq!(map.get(&key(0, 2)), Some(&2));
+    assert_eq!(map.len(), 2);
+    map.insert(key(0, 1), 11);
+    assert_eq!(map.get(&key(0, 1)), Some(&11));
+}
+
+#[test]
+fn probe_wraps_around() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    // find a group whose entries wrap around the end of the smallest table, the buckets
+    // are iterated in order, so a wrapped entry comes before the first one
+    let (group, mut map) = (0..)
+        .map(|group| {
+            let mut map = DMap::new();
+            (0..3).for_each(|id| {
+                map.insert(key(group, id), id);
+            });
+            (group, map)
+        })
+        .find(|(_, map)| map.keys().next().unwrap().id != 0)
+        .unwrap();
+    assert_eq!(map.capacity(), 6);
+    for id in 0..3 {
+        assert_eq!(map.get(&key(group, id)), Some(&id));
+    }
+    assert_eq!(map.remove(&key(group, 0)), Some(0));
+    assert_eq!(map.get(&key(group, 1)), Some(&1));
+    assert_eq!(map.get(&key(group, 2)), Some(&2));
+    assert_eq!(map.remove(&key(group, 2)), Some(2));
+    assert_eq!(map.remove(&key(group, 1)), Some(1));
+    assert!(map.is_empty());
+    assert_eq!(map.iter().count(), 0);
+    // no entry is left behind to end a probe early
+    map.insert(key(group, 3), 3);
+    assert_eq!(map.get(&key(group, 3)), Some(&3));
+}
+
+#[test]
+fn resize_keeps_entries() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let mut map = DMap::with_capacity(4);
+    assert_eq!(map.capacity(), 6);
+    for i in 0..1000u64 {
+        assert_eq!(map.insert(i, i * 2), None);
+        assert!(map.len() <= map.capacity());
+    }
+    assert_eq!(map.len(), 1000);
+    assert!((0..1000).all(|i| map.get(&i) == Some(&(i * 2))));
+    map.reserve(1000);
+    assert!(map.capacity() >= 2000);
+    assert!((0..1000).all(|i| map.get(&i) == Some(&(i * 2))));
+}
+
+#[test]
+fn resize_lent_map() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let mut map = DMap::new();
+    map.insert(0u64, 0u64);
+    // the map is owned by another domain, which lends it to this one to keep inserting
+    map.move_to(40);
+    {
+        let _lend = Lend::new(&map, 1);
+        for i in 1..100 {
+            map.insert(i, i);
+        }
+        assert!((0..100).all(|i| map.get(&i) == Some(&i)));
+    }
+    map.move_to(1);
+}
+
+#[test]
+fn clear_drops_entries() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let mut map = DMap::new();
+    for i in 0..10u64 {
+        map.insert(i, Counted);
+    }
+    map.clear();
+    assert_eq!(DROPS.load(Ordering::SeqCst), 10);
+    assert!(map.is_empty());
+    map.insert(0, Counted);
+    map.move_to(41);
+    free_domain_shared_data(41, FreeShared::Free);
+    core::mem::forget(map);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 11);
+}
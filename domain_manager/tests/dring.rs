@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use domain_manager::sheap::{free_domain_shared_data, init_shared_heap, FreeShared};
+use shared_heap::{DRing, PopError, PushError, RingWaiter, SharedData};
+
+/// Blocks the threads of the test like tasks, a thread only returns from `wait` once it
+/// is woken up.
+struct ThreadWaiter {
+    woken: Mutex<BTreeSet<usize>>,
+    wakeup: Condvar,
+}
+
+static WAITER: ThreadWaiter = ThreadWaiter {
+    woken: Mutex::new(BTreeSet::new()),
+    wakeup: Condvar::new(),
+};
+
+thread_local! {
+    static TID: usize = {
+        static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+        NEXT_TID.fetch_add(1, Ordering::Relaxed)
+    };
+}
+
+impl RingWaiter for ThreadWaiter {
+    fn current_tid(&self) -> Option<usize> {
+        Some(TID.with(|tid| *tid))
+    }
+
+    fn wait(&self) {
+        let tid = TID.with(|tid| *tid);
+        let mut woken = self.woken.lock().unwrap();
+        while !woken.remove(&tid) {
+            woken = self.wakeup.wait(woken).unwrap();
+        }
+    }
+
+    fn wake(&self, tid: usize) {
+        self.woken.lock().unwrap().insert(tid);
+        self.wakeup.notify_all();
+    }
+}
+
+fn init() {
+    init_shared_heap(|| 0, &WAITER);
+}
+
+/// Give the thread time to block on the ring.
+fn let_block() {
+    thread::sleep(Duration::from_millis(50));
+}
+
+#[test]
+fn spsc_wraparound() {
+    init();
+    let (mut producer, mut consumer) = DRing::<u32, 4>::spsc();
+    assert_eq!(consumer.try_pop(), Err(PopError::Empty));
+    let mut next = 0;
+    // the positions go around the ring many times
+    for round in 0..100 {
+        let len = round % 4 + 1;
+        for i in 0..len {
+            producer.try_push(next + i).unwrap();
+        }
+        if len == 4 {
+            assert_eq!(producer.try_push(0), Err(PushError::Full(0)));
+        }
+        assert_eq!(consumer.len(), len as usize);
+        for i in 0..len {
+            assert_eq!(consumer.try_pop(), Ok(next + i));
+        }
+        assert_eq!(consumer.try_pop(), Err(PopError::Empty));
+        next += len;
+    }
+    drop(producer);
+    assert_eq!(consumer.try_pop(), Err(PopError::Closed));
+}
+
+#[test]
+fn mpsc_wraparound() {
+    init();
+    let (producer, mut consumer) = DRing::<u64, 8>::mpsc();
+    let threads = (0..4u64)
+        .map(|k| {
+            let mut producer = producer.clone();
+            thread::spawn(move || {
+                for i in 0..10000 {
+                    producer.push(k << 32 | i).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(producer);
+    let mut last = [None; 4];
+    let mut count = 0;
+    while let Ok(value) = consumer.pop() {
+        // the elements of one producer keep their order
+        let (k, i) = ((value >> 32) as usize, value & u32::MAX as u64);
+        assert!(last[k].map_or(true, |last| last < i));
+        last[k] = Some(i);
+        count += 1;
+    }
+    threads
+        .into_iter()
+        .for_each(|thread| thread.join().unwrap());
+    assert_eq!(count, 40000);
+    assert_eq!(last, [Some(9999); 4]);
+}
+
+#[test]
+fn close_wakes_blocked_consumer() {
+    init();
+    let (producer, mut consumer) = DRing::<u8, 4>::spsc();
+    let thread = thread::spawn(move || consumer.pop());
+    let_block();
+    drop(producer);
+    assert_eq!(thread.join().unwrap(), Err(PopError::Closed));
+}
+
+#[test]
+fn crash_wakes_blocked_consumer() {
+    init();
+    let (producer, mut consumer) = DRing::<u8, 4>::spsc();
+    let thread = thread::spawn(move || consumer.pop());
+    producer.move_to(30);
+    let_block();
+    free_domain_shared_data(30, FreeShared::Free);
+    core::mem::forget(producer);
+    assert_eq!(thread.join().unwrap(), Err(PopError::Closed));
+}
+
+#[test]
+fn crash_wakes_blocked_producer() {
+    init();
+    let (mut producer, consumer) = DRing::<u8, 2>::spsc();
+    producer.try_push(1).unwrap();
+    producer.try_push(2).unwrap();
+    let thread = thread::spawn(move || producer.push(3));
+    consumer.move_to(31);
+    let_block();
+    free_domain_shared_data(31, FreeShared::Free);
+    core::mem::forget(consumer);
+    assert_eq!(thread.join().unwrap(), Err(PushError::Closed(3)));
+}
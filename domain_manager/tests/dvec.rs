@@ -0,0 +1,40 @@
+#![feature(min_specialization)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use shared_heap::{CustomDrop, DBox, DVec, Lend, SharedData};
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Not `Clone`, so it can only be put in a vector by `DVec::from_fn`.
+struct Unique(usize);
+
+impl CustomDrop for Unique {
+    fn custom_drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn from_fn_without_clone() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let boxes = DVec::from_fn(3, |i| DBox::new(Unique(i)));
+    assert_eq!(boxes.len(), 3);
+    assert!(boxes.iter().enumerate().all(|(i, dbox)| dbox.0 == i));
+    let nested = DVec::from_fn(2, |i| DVec::from_fn(i + 1, Unique));
+    assert_eq!(nested[1].len(), 2);
+    assert_eq!(nested[1][1].0, 1);
+    // the elements move and are dropped with the vectors
+    assert_eq!(boxes.move_to(7), 1);
+    assert_eq!(nested.move_to(7), 1);
+    {
+        // the new owner lends the vector back to be inspected
+        let _lend = Lend::new(&boxes, 1);
+        assert!(boxes.iter().all(|dbox| dbox.domain_id() == 7));
+    }
+    free_domain_shared_data(7, FreeShared::Free);
+    core::mem::forget(boxes);
+    core::mem::forget(nested);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 6);
+}
@@ -0,0 +1,122 @@
+use domain_manager::graph::{BootError, DomainGraph, GraphError};
+
+fn graph(domains: &[(&str, &[&str])]) -> DomainGraph {
+    let mut graph = DomainGraph::new();
+    for (name, dependencies) in domains {
+        graph.add_domain(name, dependencies).unwrap();
+    }
+    graph
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// `top` uses `left` and `right`, which both use `bottom`.
+fn diamond() -> DomainGraph {
+    graph(&[
+        ("top", &["left", "right"]),
+        ("left", &["bottom"]),
+        ("right", &["bottom"]),
+        ("bottom", &[]),
+    ])
+}
+
+#[test]
+fn diamond_boots_shared_dependency_once() {
+    let graph = diamond();
+    let order = graph.boot_order().unwrap();
+    assert_eq!(order, names(&["bottom", "left", "right", "top"]));
+    let mut started = vec![];
+    graph
+        .boot(|name| {
+            started.push(name.to_string());
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+    assert_eq!(started, order);
+}
+
+#[test]
+fn self_cycle() {
+    let graph = graph(&[("loop", &["loop"]), ("other", &[])]);
+    assert_eq!(graph.boot_order(), Err(GraphError::Cycle(names(&["loop"]))));
+    assert_eq!(
+        graph.boot(|_| Ok::<_, ()>(())),
+        Err(BootError::Graph(GraphError::Cycle(names(&["loop"]))))
+    );
+}
+
+#[test]
+fn longer_cycle() {
+    // `a` leads into the cycle but is not part of it
+    let graph = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &["d"]), ("d", &["b"])]);
+    assert_eq!(
+        graph.boot_order(),
+        Err(GraphError::Cycle(names(&["b", "c", "d"])))
+    );
+    assert!(matches!(graph.dependents("d"), Err(GraphError::Cycle(_))));
+}
+
+#[test]
+fn missing_dependency() {
+    let mut graph = graph(&[("fs", &["cache"]), ("cache", &["blk"])]);
+    assert_eq!(
+        graph.boot_order(),
+        Err(GraphError::MissingDependency {
+            domain: "cache".to_string(),
+            dependency: "blk".to_string(),
+        })
+    );
+    // the dependency may be added later
+    graph.add_domain::<&str>("blk", &[]).unwrap();
+    assert_eq!(graph.boot_order().unwrap(), names(&["blk", "cache", "fs"]));
+    assert!(graph.remove_domain("blk"));
+    assert!(matches!(
+        graph.boot_order(),
+        Err(GraphError::MissingDependency { .. })
+    ));
+}
+
+#[test]
+fn restart_dependents_in_boot_order() {
+    let graph = diamond();
+    assert_eq!(
+        graph.dependents("bottom").unwrap(),
+        names(&["left", "right", "top"])
+    );
+    assert_eq!(graph.dependents("right").unwrap(), names(&["top"]));
+    assert!(graph.dependents("top").unwrap().is_empty());
+    assert_eq!(
+        graph.dependents("none"),
+        Err(GraphError::NotFound("none".to_string()))
+    );
+
+    let mut restarted = vec![];
+    let domains = graph
+        .restart("bottom", |name| {
+            restarted.push(name.to_string());
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+    assert_eq!(domains, names(&["bottom", "left", "right", "top"]));
+    assert_eq!(restarted, domains);
+
+    // the domains after the one which fails are not restarted
+    let mut restarted = vec![];
+    let result = graph.restart("bottom", |name| {
+        restarted.push(name.to_string());
+        if name == "right" {
+            return Err(-1);
+        }
+        Ok(())
+    });
+    assert_eq!(
+        result,
+        Err(BootError::Start {
+            domain: "right".to_string(),
+            error: -1,
+        })
+    );
+    assert_eq!(restarted, names(&["bottom", "left", "right"]));
+}
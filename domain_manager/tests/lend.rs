@@ -0,0 +1,78 @@
+#![cfg(feature = "debug")]
+
+use core::mem::ManuallyDrop;
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use shared_heap::{DBox, DMap, DVec, Lend, SharedData};
+
+#[test]
+fn read_borrowed_data() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let buf = DVec::from_slice(b"data");
+    let nested = DBox::new(DBox::new(7u64));
+    let mut map = DMap::new();
+    map.insert(1u64, 2u64);
+    for data in [&buf as &dyn SharedData, &nested, &map] {
+        data.move_to(50);
+    }
+    // domain 50 calls this one with its data passed by reference
+    let lends = [
+        Lend::new(&buf, 1),
+        Lend::new(&nested, 1),
+        Lend::new(&map, 1),
+    ];
+    assert_eq!(buf.as_slice(), b"data");
+    assert_eq!(**nested, 7);
+    assert_eq!(map.get(&1), Some(&2));
+    // the data is lent until the last borrow ends
+    let again = Lend::new(&buf, 1);
+    drop(lends);
+    assert_eq!(buf.as_slice(), b"data");
+    drop(again);
+    free_domain_shared_data(50, FreeShared::Free);
+    core::mem::forget(buf);
+    core::mem::forget(nested);
+    core::mem::forget(map);
+}
+
+#[test]
+fn read_own_data_lent_to_itself() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let buf = DVec::from_slice(b"own");
+    let lend = Lend::new(&buf, 1);
+    assert_eq!(buf.as_slice(), b"own");
+    drop(lend);
+    assert_eq!(buf.as_slice(), b"own");
+}
+
+#[test]
+#[should_panic(expected = "has been moved to domain 51")]
+fn read_moved_data() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let buf = ManuallyDrop::new(DVec::from_slice(b"data"));
+    // moved by the value, `ManuallyDrop` itself owns no shared data
+    (*buf).move_to(51);
+    let _ = buf[0];
+}
+
+#[test]
+#[should_panic(expected = "has been moved to domain 52")]
+fn read_after_borrow_ends() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let dbox = ManuallyDrop::new(DBox::new(3u64));
+    (*dbox).move_to(52);
+    drop(Lend::new(&*dbox, 1));
+    let _ = **dbox;
+}
+
+#[test]
+#[should_panic(expected = "has been moved to domain 53")]
+fn write_borrowed_data() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let mut dbox = ManuallyDrop::new(DBox::new(3u64));
+    (*dbox).move_to(53);
+    let _lend = Lend::new(&*dbox, 1);
+    // a borrowed argument is only read
+    **dbox = 4;
+}
+
@@ -0,0 +1,164 @@
+#![feature(allocator_api)]
+
+use std::sync::{Arc, Mutex};
+
+use domain_manager::{
+    persist::{attach_restored_database, restore_domain_databases, save_domain_databases},
+    storage_heap::{create_domain_database, get_domain_database, DOMAIN_DATA_ALLOCATOR},
+};
+use storage::{
+    CustomStorge, DomainDataStorage, Persist, PersistDevice, Persisted, StorageEntry, StorageError,
+    StorageResult,
+};
+
+const CAPACITY: usize = 8192;
+
+/// A device in memory, a torn write stops after the given number of bytes.
+struct MemDevice {
+    data: Mutex<Vec<u8>>,
+    tear: Mutex<Option<usize>>,
+}
+
+impl MemDevice {
+    fn new() -> Self {
+        Self {
+            data: Mutex::new(vec![0; CAPACITY]),
+            tear: Mutex::new(None),
+        }
+    }
+
+    /// Whether the header of the slot has been written.
+    fn slot_written(&self, slot: usize) -> bool {
+        let offset = slot * CAPACITY / 2;
+        self.data.lock().unwrap()[offset..offset + 8] == *b"DOMSTORE"
+    }
+}
+
+impl PersistDevice for MemDevice {
+    fn capacity(&self) -> usize {
+        CAPACITY
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> StorageResult<()> {
+        buf.copy_from_slice(&self.data.lock().unwrap()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> StorageResult<()> {
+        let len = self.tear.lock().unwrap().take().unwrap_or(buf.len());
+        self.data.lock().unwrap()[offset..offset + len].copy_from_slice(&buf[..len]);
+        Ok(())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Counter(u64);
+
+impl Persist for Counter {
+    const VERSION: u32 = 1;
+
+    fn save(&self, buf: &mut [u8]) -> Option<usize> {
+        // the domain may use its storage while it is saved
+        assert!(get_domain_database(1).is_some());
+        buf.get_mut(..8)?.copy_from_slice(&self.0.to_le_bytes());
+        Some(8)
+    }
+
+    fn load(_version: u32, data: &[u8]) -> Option<Self> {
+        Some(Counter(u64::from_le_bytes(data.try_into().ok()?)))
+    }
+}
+
+fn set_counter(value: u64) {
+    let entry = StorageEntry::persistent(Arc::new_in(Counter(value), CustomStorge));
+    get_domain_database(1)
+        .unwrap()
+        .insert("counter", entry)
+        .unwrap();
+}
+
+/// Restore the device and return the counter of the domain named "counter".
+fn restored_counter(device: &MemDevice, domain_id: u64) -> Counter {
+    assert_eq!(restore_domain_databases(device), Ok(1));
+    create_domain_database(domain_id);
+    assert!(attach_restored_database(domain_id, "counter"));
+    let entry = get_domain_database(domain_id)
+        .unwrap()
+        .get("counter")
+        .unwrap()
+        .unwrap();
+    let persisted = entry.persisted().unwrap();
+    assert_eq!(persisted.version, Counter::VERSION);
+    Counter::load(persisted.version, &persisted.data).unwrap()
+}
+
+#[test]
+fn save_restore_round_trip() {
+    storage::init_data_allocator(DOMAIN_DATA_ALLOCATOR);
+    let device = MemDevice::new();
+    assert_eq!(restore_domain_databases(&device), Ok(0));
+    create_domain_database(1);
+    let name_of = |domain_id| (domain_id == 1).then(|| "counter".to_string());
+
+    // the images are written to the slots in turn
+    set_counter(1);
+    assert_eq!(save_domain_databases(&device, name_of), Ok(1));
+    assert!(device.slot_written(0));
+    assert!(!device.slot_written(1));
+    set_counter(2);
+    assert_eq!(save_domain_databases(&device, name_of), Ok(1));
+    assert!(device.slot_written(1));
+    assert_eq!(restored_counter(&device, 2), Counter(2));
+
+    // the third image overwrites the first one, but the write is torn after its header
+    set_counter(3);
+    *device.tear.lock().unwrap() = Some(64);
+    assert_eq!(save_domain_databases(&device, name_of), Ok(1));
+    assert_eq!(restored_counter(&device, 3), Counter(2));
+
+    // a complete write is restored
+    set_counter(4);
+    assert_eq!(save_domain_databases(&device, name_of), Ok(1));
+    assert_eq!(restored_counter(&device, 4), Counter(4));
+
+    // both slots damaged
+    for offset in [60, CAPACITY / 2 + 60] {
+        device.data.lock().unwrap()[offset] ^= 0xff;
+    }
+    assert_eq!(
+        restore_domain_databases(&device),
+        Err(StorageError::Corrupted)
+    );
+}
+
+#[test]
+fn restored_value_is_loaded_by_its_domain() {
+    storage::init_data_allocator(DOMAIN_DATA_ALLOCATOR);
+    create_domain_database(5);
+    storage::init_database(get_domain_database(5).unwrap());
+    let mut data = Vec::new_in(CustomStorge);
+    data.extend_from_slice(&9u64.to_le_bytes());
+    let persisted = Persisted {
+        version: Counter::VERSION,
+        data,
+    };
+    get_domain_database(5)
+        .unwrap()
+        .insert("counter", StorageEntry::restored(persisted))
+        .unwrap();
+    // the restored bytes are not a `Counter` until they are loaded
+    assert_eq!(
+        storage::get::<Counter>("counter").err(),
+        Some(StorageError::NotLoaded)
+    );
+    let counter = storage::get_or_insert_persistent("counter", || Counter(0)).unwrap();
+    assert_eq!(*counter, Counter(9));
+    assert_eq!(
+        *storage::get::<Counter>("counter").unwrap().unwrap(),
+        Counter(9)
+    );
+}
@@ -0,0 +1,78 @@
+#![feature(min_specialization)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use domain_manager::sheap::{free_domain_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+use shared_heap::{CustomDrop, DBox, DVec, SharedData};
+
+const CANARY: u64 = 0xc0ff_ee00;
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+static NESTED_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone)]
+struct Canary(u64);
+
+impl CustomDrop for Canary {
+    fn custom_drop(&mut self) {
+        assert_eq!(self.0, CANARY, "dropped uninitialized data");
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+struct Nested(u64);
+
+impl CustomDrop for Nested {
+    fn custom_drop(&mut self) {
+        assert_eq!(self.0, CANARY, "dropped freed data");
+        NESTED_DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn crash_drops_nested_data_once() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let boxed = DBox::new(DBox::new(Nested(CANARY)));
+    let mut boxes = DVec::<DBox<Nested>>::new_uninit(3);
+    boxes.iter_mut().for_each(|slot| {
+        slot.write(DBox::new(Nested(CANARY)));
+    });
+    let boxes = unsafe { boxes.assume_init() };
+    let vec = DBox::new(DVec::new(Nested(CANARY), 2));
+    // the nested objects are moved to the domain with their owners
+    boxed.move_to(4);
+    boxes.move_to(4);
+    vec.move_to(4);
+    free_domain_shared_data(4, FreeShared::Free);
+    core::mem::forget(boxed);
+    core::mem::forget(boxes);
+    core::mem::forget(vec);
+    assert_eq!(NESTED_DROPS.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn crash_before_init_drops_nothing() {
+    shared_heap::init(SHARED_HEAP_ALLOCATOR, 1);
+    let dbox = DBox::<Canary>::new_uninit();
+    let dvec = DVec::<Canary>::new_uninit(4);
+    unsafe {
+        core::ptr::write_bytes(dbox.as_ptr() as *mut Canary, 0xab, 1);
+        core::ptr::write_bytes(dvec.as_slice().as_ptr() as *mut Canary, 0xab, 4);
+    }
+    dbox.move_to(2);
+    dvec.move_to(2);
+    free_domain_shared_data(2, FreeShared::Free);
+    core::mem::forget(dbox);
+    core::mem::forget(dvec);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+    let dbox = DBox::new(Canary(CANARY));
+    let dvec = DVec::new(Canary(CANARY), 4);
+    dbox.move_to(3);
+    dvec.move_to(3);
+    free_domain_shared_data(3, FreeShared::Free);
+    core::mem::forget(dbox);
+    core::mem::forget(dvec);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 5);
+}
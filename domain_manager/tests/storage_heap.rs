@@ -0,0 +1,72 @@
+#![feature(allocator_api)]
+
+use std::sync::Arc;
+
+use domain_manager::storage_heap::{DomainDataMap, DOMAIN_DATA_ALLOCATOR};
+use storage::{CustomStorge, DomainDataStorage, StorageEntry, StorageError, TypeTag};
+
+fn map_with(key: &str, value: u64) -> (DomainDataMap, StorageEntry) {
+    storage::init_data_allocator(DOMAIN_DATA_ALLOCATOR);
+    let map = DomainDataMap::new();
+    let entry = StorageEntry::new(Arc::new_in(value, CustomStorge));
+    map.insert(key, entry.clone()).unwrap();
+    (map, entry)
+}
+
+#[test]
+fn remove_unique_with_outside_clone() {
+    let (map, entry) = map_with("key", 1);
+    assert_eq!(
+        map.remove_unique("key", TypeTag::of::<u64>()).unwrap_err(),
+        StorageError::InUse { refs: 1 }
+    );
+    assert!(map.get("key").unwrap().is_some());
+    drop(entry);
+    let removed = map
+        .remove_unique("key", TypeTag::of::<u64>())
+        .unwrap()
+        .unwrap();
+    assert_eq!(removed.strong_count(), 1);
+    assert!(map.get("key").unwrap().is_none());
+    assert!(map
+        .remove_unique("key", TypeTag::of::<u64>())
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn remove_unique_held_by_committed_and_pending() {
+    let (map, entry) = map_with("key", 2);
+    map.begin().unwrap();
+    // the open transaction holds the same value as the committed table
+    map.insert("key", entry.clone()).unwrap();
+    assert_eq!(
+        map.remove_unique("key", TypeTag::of::<u64>()).unwrap_err(),
+        StorageError::InUse { refs: 1 }
+    );
+    drop(entry);
+    let removed = map
+        .remove_unique("key", TypeTag::of::<u64>())
+        .unwrap()
+        .unwrap();
+    // the committed table keeps its clone until the commit
+    assert_eq!(removed.strong_count(), 2);
+    assert!(map.get("key").unwrap().is_none());
+    map.commit().unwrap();
+    assert_eq!(removed.strong_count(), 1);
+    assert_eq!(map.len(), 0);
+}
+
+#[test]
+fn remove_unique_type_mismatch() {
+    let (map, entry) = map_with("key", 3);
+    drop(entry);
+    assert_eq!(
+        map.remove_unique("key", TypeTag::of::<u32>()).unwrap_err(),
+        StorageError::TypeMismatch {
+            expected: TypeTag::of::<u32>(),
+            found: TypeTag::of::<u64>(),
+        }
+    );
+    assert!(map.get("key").unwrap().is_some());
+}
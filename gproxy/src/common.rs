@@ -69,6 +69,7 @@ pub struct FuncInfo {
     pub output: ReturnType,
     pub fn_args: Vec<FnArg>,
     pub arg_domain_change: Vec<TokenStream>,
+    pub arg_lend: Vec<TokenStream>,
 }
 
 pub fn collect_func_info(func: &TraitItemFn) -> FuncInfo {
@@ -95,6 +96,7 @@ pub fn collect_func_info(func: &TraitItemFn) -> FuncInfo {
     let mut fn_args = vec![];
 
     let mut arg_domain_change = vec![];
+    let mut arg_lend = vec![];
 
     let input_argv = input
         .iter()
@@ -107,12 +109,27 @@ pub fn collect_func_info(func: &TraitItemFn) -> FuncInfo {
                     syn::Pat::Ident(ident) => {
                         fn_args.push(arg.clone());
                         let name = ident.ident.clone();
-                        if ty.starts_with("DBox") || ty.starts_with("DVec") {
+                        if ty.starts_with("DBox")
+                            || ty.starts_with("DVec")
+                            || ty.starts_with("DMap")
+                            || ty.starts_with("DRing")
+                        {
                             let change_code = quote!(
                                 let old_id = #name.move_to(id);
                             );
                             arg_domain_change.push(change_code);
                         }
+                        // the callee reads the data of the caller while it runs
+                        if ty.starts_with("& DBox")
+                            || ty.starts_with("& DVec")
+                            || ty.starts_with("& DMap")
+                        {
+                            let lend = Ident::new(&format!("__lend_{}", name), name.span());
+                            let lend_code = quote!(
+                                let #lend = shared_heap::Lend::new(#name, id);
+                            );
+                            arg_lend.push(lend_code);
+                        }
                         name
                     }
                     _ => {
@@ -135,11 +152,13 @@ pub fn collect_func_info(func: &TraitItemFn) -> FuncInfo {
         output: out_put,
         fn_args,
         arg_domain_change,
+        arg_lend,
     }
 }
 
 pub struct TrampolineInfo {
     pub get_domain_id: TokenStream,
+    pub lend_args: TokenStream,
     pub check_code: TokenStream,
     pub call_move_to: TokenStream,
 }
@@ -152,11 +171,16 @@ pub struct TrampolineArg<'a> {
     pub input_argv: Vec<Ident>,
     pub fn_args: Vec<FnArg>,
     pub arg_domain_change: Vec<TokenStream>,
+    pub arg_lend: Vec<TokenStream>,
     pub out_put: ReturnType,
     pub no_check: bool,
 }
-pub fn gen_trampoline_info(arg_domain_change: &[TokenStream], no_check: bool) -> TrampolineInfo {
-    let get_domain_id = if arg_domain_change.is_empty() {
+pub fn gen_trampoline_info(
+    arg_domain_change: &[TokenStream],
+    arg_lend: &[TokenStream],
+    no_check: bool,
+) -> TrampolineInfo {
+    let get_domain_id = if arg_domain_change.is_empty() && arg_lend.is_empty() {
         quote!()
     } else {
         let x1 = quote!(
@@ -183,8 +207,13 @@ pub fn gen_trampoline_info(arg_domain_change: &[TokenStream], no_check: bool) ->
         x2
     };
 
+    let lend_args = quote!(
+        #(#arg_lend)*
+    );
+
     TrampolineInfo {
         get_domain_id,
+        lend_args,
         check_code,
         call_move_to,
     }
@@ -191,6 +191,7 @@ fn impl_func_code(
         output,
         fn_args,
         arg_domain_change,
+        arg_lend,
     } = collect_func_info(func);
 
     match func_name.to_string().as_str() {
@@ -215,6 +216,7 @@ fn impl_func_code(
                 input_argv,
                 fn_args,
                 arg_domain_change,
+                arg_lend,
                 out_put: output,
                 no_check,
             });
@@ -239,15 +241,17 @@ fn gen_trampoline(arg: TrampolineArg) -> TokenStream {
         input_argv,
         fn_args: _fn_args,
         arg_domain_change,
+        arg_lend,
         out_put: _out_put,
         no_check,
     } = arg;
 
     let TrampolineInfo {
         get_domain_id,
+        lend_args,
         check_code,
         call_move_to,
-    } = gen_trampoline_info(&arg_domain_change, no_check);
+    } = gen_trampoline_info(&arg_domain_change, &arg_lend, no_check);
 
     quote! (
             let idx = self.srcu_lock.read_lock();
@@ -255,6 +259,7 @@ fn gen_trampoline(arg: TrampolineArg) -> TokenStream {
             #check_code
             #get_domain_id
             #(#arg_domain_change)*
+            #lend_args
             let res = r_domain.#func_name(#(#input_argv),*).map(|r| {
                 #call_move_to
                 r
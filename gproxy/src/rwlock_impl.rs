@@ -236,6 +236,7 @@ fn impl_func_code_rwlock(
         output,
         fn_args,
         arg_domain_change,
+        arg_lend,
     } = collect_func_info(func);
 
     match func_name.to_string().as_str() {
@@ -260,6 +261,7 @@ fn impl_func_code_rwlock(
                 input_argv,
                 fn_args,
                 arg_domain_change,
+                arg_lend,
                 out_put: output,
                 no_check,
             });
@@ -284,11 +286,12 @@ fn gen_trampoline_rwlock(arg: TrampolineArg) -> (TokenStream, TokenStream) {
         input_argv,
         fn_args,
         arg_domain_change,
+        arg_lend,
         out_put,
         no_check,
     } = arg;
 
-    let info = gen_trampoline_info(&arg_domain_change, no_check);
+    let info = gen_trampoline_info(&arg_domain_change, &arg_lend, no_check);
 
     let (inner_call_code, __ident_no_lock, __ident_with_lock) = impl_inner_code(
         has_recovery,
@@ -330,6 +333,7 @@ fn impl_inner_code(
 
     let TrampolineInfo {
         get_domain_id,
+        lend_args,
         check_code,
         call_move_to,
     } = info;
@@ -339,6 +343,7 @@ fn impl_inner_code(
         #check_code
         #get_domain_id
         #(#arg_domain_change)*
+        #lend_args
         let res = r_domain.#func_name(#(#input_argv),*).map(|r| {
             #call_move_to
             r
@@ -74,6 +74,7 @@ fn impl_unwind_func_code(func: &TraitItemFn) -> TokenStream {
         output: _,
         fn_args: _,
         arg_domain_change: _,
+        arg_lend: _,
     } = collect_func_info(func);
     let name = func.sig.ident.clone();
     let mut attr = func.attrs.clone();
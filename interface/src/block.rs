@@ -1,8 +1,10 @@
+use alloc::sync::Arc;
 use core::ops::Range;
 
 use downcast_rs::{impl_downcast, DowncastSync};
 use gproxy::proxy;
 use shared_heap::DVec;
+use storage::{PersistDevice, StorageError, StorageResult};
 
 use super::AlienResult;
 use crate::{Basic, DeviceBase};
@@ -17,3 +19,84 @@ pub trait BlkDeviceDomain: DeviceBase + Basic + DowncastSync {
 }
 
 impl_downcast!(sync  BlkDeviceDomain);
+
+/// A range of blocks of a block device which keeps the persistent domain storage.
+pub struct BlkPersistRegion {
+    device: Arc<dyn BlkDeviceDomain>,
+    blocks: Range<u32>,
+    block_size: usize,
+}
+
+impl BlkPersistRegion {
+    pub fn new(device: Arc<dyn BlkDeviceDomain>, blocks: Range<u32>, block_size: usize) -> Self {
+        Self {
+            device,
+            blocks,
+            block_size,
+        }
+    }
+
+    /// Call `f` with each block covering `offset..offset + len`, the offset in the block and
+    /// the range of the caller's buffer it maps to.
+    fn for_each_block<F>(&self, offset: usize, len: usize, mut f: F) -> StorageResult<()>
+    where
+        F: FnMut(u32, Range<usize>, Range<usize>) -> StorageResult<()>,
+    {
+        if offset + len > self.capacity() {
+            return Err(StorageError::NoSpace);
+        }
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let block = self.blocks.start + (pos / self.block_size) as u32;
+            let start = pos % self.block_size;
+            let n = (self.block_size - start).min(len - done);
+            f(block, start..start + n, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl PersistDevice for BlkPersistRegion {
+    fn capacity(&self) -> usize {
+        self.blocks.len() * self.block_size
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> StorageResult<()> {
+        let mut data = Some(DVec::new(0u8, self.block_size));
+        self.for_each_block(offset, buf.len(), |block, range, buf_range| {
+            let block_data = self
+                .device
+                .read_block(block, data.take().unwrap())
+                .map_err(|_| StorageError::Io)?;
+            buf[buf_range].copy_from_slice(&block_data.as_slice()[range]);
+            data = Some(block_data);
+            Ok(())
+        })
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> StorageResult<()> {
+        let mut data = Some(DVec::new(0u8, self.block_size));
+        self.for_each_block(offset, buf.len(), |block, range, buf_range| {
+            let mut block_data = data.take().unwrap();
+            if range.len() != self.block_size {
+                // keep the rest of a partially written block
+                block_data = self
+                    .device
+                    .read_block(block, block_data)
+                    .map_err(|_| StorageError::Io)?;
+            }
+            block_data.as_mut_slice()[range].copy_from_slice(&buf[buf_range]);
+            self.device
+                .write_block(block, &block_data)
+                .map_err(|_| StorageError::Io)?;
+            data = Some(block_data);
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.device.flush().map_err(|_| StorageError::Io)
+    }
+}
@@ -1,6 +1,10 @@
+use alloc::sync::Arc;
+use core::ops::Range;
+
 use downcast_rs::{impl_downcast, DowncastSync};
 use gproxy::proxy;
 use shared_heap::DVec;
+use storage::{PersistDevice, StorageError, StorageResult};
 
 use super::AlienResult;
 use crate::{Basic, DeviceBase};
@@ -15,3 +19,57 @@ pub trait CacheBlkDeviceDomain: DeviceBase + Basic + DowncastSync {
 }
 
 impl_downcast!(sync CacheBlkDeviceDomain);
+
+/// A byte range of a cached block device which keeps the persistent domain storage.
+pub struct CachePersistRegion {
+    device: Arc<dyn CacheBlkDeviceDomain>,
+    range: Range<u64>,
+}
+
+impl CachePersistRegion {
+    pub fn new(device: Arc<dyn CacheBlkDeviceDomain>, range: Range<u64>) -> Self {
+        Self { device, range }
+    }
+
+    fn offset(&self, offset: usize, len: usize) -> StorageResult<u64> {
+        if offset + len > self.capacity() {
+            return Err(StorageError::NoSpace);
+        }
+        Ok(self.range.start + offset as u64)
+    }
+}
+
+impl PersistDevice for CachePersistRegion {
+    fn capacity(&self) -> usize {
+        (self.range.end - self.range.start) as usize
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> StorageResult<()> {
+        let offset = self.offset(offset, buf.len())?;
+        let data = self
+            .device
+            .read(offset, DVec::new(0u8, buf.len()))
+            .map_err(|_| StorageError::Io)?;
+        if data.len() != buf.len() {
+            return Err(StorageError::Io);
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> StorageResult<()> {
+        let offset = self.offset(offset, buf.len())?;
+        let written = self
+            .device
+            .write(offset, &DVec::from_slice(buf))
+            .map_err(|_| StorageError::Io)?;
+        if written != buf.len() {
+            return Err(StorageError::Io);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.device.flush().map_err(|_| StorageError::Io)
+    }
+}
@@ -1,5 +1,6 @@
 #![no_std]
 #![feature(trait_upcasting)]
+#![feature(min_specialization)]
 mod block;
 mod buf_input;
 mod buf_uart;
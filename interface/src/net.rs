@@ -6,7 +6,7 @@ use pconst::{
     io::PollEvents,
     net::{Domain, ShutdownFlag, SocketAddrIn, SocketType},
 };
-use shared_heap::{DBox, DVec};
+use shared_heap::{CustomDrop, DBox, DVec, SharedData};
 
 use super::AlienResult;
 use crate::{Basic, DeviceBase};
@@ -58,6 +58,7 @@ pub trait NetDomain: DeviceBase + Basic + DowncastSync {
     fn poll(&self, socket_id: SocketID, events: PollEvents) -> AlienResult<PollEvents>;
 }
 
+#[derive(CustomDrop, SharedData)]
 pub struct SocketArgTuple {
     pub buf: DVec<u8>,
     pub addr: DBox<SocketAddrIn>,
@@ -0,0 +1,302 @@
+//! Compressed domain images, an LZ4 or zstd frame holding the ELF file.
+//!
+//! The frame must declare the size of its content, so the buffer is allocated once and a
+//! damaged frame cannot make the kernel allocate without bound. The checksums of the
+//! frame are not checked, a signed image is authenticated before it is decompressed.
+use alloc::{vec, vec::Vec};
+use core::mem::size_of;
+
+use ruzstd::{frame::read_frame_header, FrameDecoder};
+
+use crate::{LoaderError, Result};
+
+const LZ4_MAGIC: u32 = 0x184D_2204;
+const ZSTD_MAGIC: u32 = ruzstd::frame::MAGIC_NUM;
+/// The largest ELF file a compressed image may declare.
+const MAX_IMAGE_SIZE: usize = 256 << 20;
+/// The window of linked LZ4 blocks.
+const LZ4_WINDOW: usize = 64 << 10;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Compression {
+    Lz4,
+    Zstd,
+}
+
+/// The decompressed ELF file, kept 8-byte aligned for the ELF parser.
+pub(crate) struct Decompressed {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Decompressed {
+    fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(size_of::<u64>())],
+            len,
+        }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        // SAFETY: the words hold at least `len` bytes
+        unsafe { core::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the words hold at least `len` bytes
+        unsafe { core::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+pub(crate) fn detect(image: &[u8]) -> Option<Compression> {
+    let magic = u32::from_le_bytes(image.get(..4)?.try_into().unwrap());
+    match magic {
+        LZ4_MAGIC => Some(Compression::Lz4),
+        ZSTD_MAGIC => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+/// The size of the ELF file declared by the frame.
+pub(crate) fn content_size(image: &[u8], compression: Compression) -> Result<usize> {
+    let size = match compression {
+        Compression::Lz4 => Lz4Header::read(image)?.content_size,
+        Compression::Zstd => {
+            let (frame, _) = read_frame_header(image)
+                .map_err(|_| LoaderError::Compression("bad zstd frame header"))?;
+            match frame.header.descriptor.frame_content_size_bytes() {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(frame.header.frame_content_size()),
+            }
+        }
+    };
+    let size = size.ok_or(LoaderError::Compression("the frame has no content size"))?;
+    usize::try_from(size)
+        .ok()
+        .filter(|size| *size <= MAX_IMAGE_SIZE)
+        .ok_or(LoaderError::Compression("the image is too large"))
+}
+
+pub(crate) fn decompress(image: &[u8], compression: Compression) -> Result<Decompressed> {
+    let mut elf = Decompressed::new(content_size(image, compression)?);
+    let len = match compression {
+        Compression::Lz4 => decompress_lz4(image, elf.as_mut_slice())?,
+        Compression::Zstd => FrameDecoder::new()
+            .decode_all(image, elf.as_mut_slice())
+            .map_err(|_| LoaderError::Compression("bad zstd frame"))?,
+    };
+    if len != elf.len {
+        return Err(LoaderError::Compression("the content size does not match"));
+    }
+    Ok(elf)
+}
+
+struct Lz4Header {
+    independent_blocks: bool,
+    block_checksum: bool,
+    content_size: Option<u64>,
+    /// The size of the frame header.
+    len: usize,
+}
+
+impl Lz4Header {
+    fn read(image: &[u8]) -> Result<Self> {
+        let bad_header = LoaderError::Compression("bad lz4 frame header");
+        let flags = *image.get(4).ok_or(bad_header.clone())?;
+        if flags >> 6 != 0b01 {
+            return Err(bad_header);
+        }
+        if flags & 0x1 != 0 {
+            return Err(LoaderError::Compression(
+                "lz4 dictionaries are not supported",
+            ));
+        }
+        // magic, flags, block descriptor, header checksum
+        let mut len = 4 + 1 + 1 + 1;
+        let content_size = if flags & 0x8 != 0 {
+            let bytes = image.get(6..14).ok_or(bad_header)?;
+            len += 8;
+            Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+        } else {
+            None
+        };
+        Ok(Self {
+            independent_blocks: flags & 0x20 != 0,
+            block_checksum: flags & 0x10 != 0,
+            content_size,
+            len,
+        })
+    }
+}
+
+fn decompress_lz4(image: &[u8], out: &mut [u8]) -> Result<usize> {
+    let bad_block = LoaderError::Compression("bad lz4 block");
+    let header = Lz4Header::read(image)?;
+    let mut input = image.get(header.len..).ok_or(bad_block.clone())?;
+    let mut take = |len: usize| {
+        let data = input.get(..len).ok_or(bad_block.clone())?;
+        input = &input[len..];
+        Ok::<_, LoaderError>(data)
+    };
+    let mut pos = 0;
+    loop {
+        let size = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        if size == 0 {
+            break;
+        }
+        let block = take((size & 0x7fff_ffff) as usize)?;
+        if header.block_checksum {
+            take(4)?;
+        }
+        let (prev, rest) = out.split_at_mut(pos);
+        pos += if size & 0x8000_0000 != 0 {
+            // stored without compression
+            rest.get_mut(..block.len())
+                .ok_or(bad_block.clone())?
+                .copy_from_slice(block);
+            block.len()
+        } else if header.independent_blocks {
+            lz4_flex::block::decompress_into(block, rest).map_err(|_| bad_block.clone())?
+        } else {
+            let dict = &prev[prev.len().saturating_sub(LZ4_WINDOW)..];
+            lz4_flex::block::decompress_into_with_dict(block, rest, dict)
+                .map_err(|_| bad_block.clone())?
+        };
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: u8 = 0b01 << 6;
+    const INDEPENDENT_BLOCKS: u8 = 0x20;
+    const BLOCK_CHECKSUM: u8 = 0x10;
+    const CONTENT_SIZE: u8 = 0x8;
+    const STORED: u32 = 0x8000_0000;
+
+    /// Bytes which only compress by referring to earlier data.
+    fn random(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    /// An LZ4 frame of the blocks, each with its size field.
+    fn lz4_frame(flags: u8, content_size: Option<u64>, blocks: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut frame = LZ4_MAGIC.to_le_bytes().to_vec();
+        frame.push(VERSION | flags | content_size.map_or(0, |_| CONTENT_SIZE));
+        // the largest block size, 4 MiB
+        frame.push(0x70);
+        if let Some(size) = content_size {
+            frame.extend_from_slice(&size.to_le_bytes());
+        }
+        // the header checksum is not checked
+        frame.push(0);
+        for (size, block) in blocks {
+            frame.extend_from_slice(&size.to_le_bytes());
+            frame.extend_from_slice(block);
+            if flags & BLOCK_CHECKSUM != 0 {
+                frame.extend_from_slice(&[0; 4]);
+            }
+        }
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame
+    }
+
+    fn compressed(block: Vec<u8>) -> (u32, Vec<u8>) {
+        (block.len() as u32, block)
+    }
+
+    fn stored(block: &[u8]) -> (u32, Vec<u8>) {
+        (block.len() as u32 | STORED, block.to_vec())
+    }
+
+    #[test]
+    fn lz4_linked_blocks() {
+        // the pattern repeats after 48 KiB, so each block refers to the one before it
+        let pattern = random(48 << 10, 1);
+        let data = pattern.repeat(5);
+        let blocks = data
+            .chunks(LZ4_WINDOW)
+            .enumerate()
+            .map(|(index, block)| {
+                let start = index * LZ4_WINDOW;
+                let dict = &data[start.saturating_sub(LZ4_WINDOW)..start];
+                compressed(lz4_flex::block::compress_with_dict(block, dict))
+            })
+            .collect::<Vec<_>>();
+        assert!(blocks[1..].iter().all(|(size, _)| *size < 1024));
+        let frame = lz4_frame(BLOCK_CHECKSUM, Some(data.len() as u64), &blocks);
+        assert_eq!(detect(&frame), Some(Compression::Lz4));
+        assert_eq!(content_size(&frame, Compression::Lz4), Ok(data.len()));
+        let elf = decompress(&frame, Compression::Lz4).unwrap();
+        assert_eq!(elf.as_slice(), data);
+        // the same blocks cannot be read without the data before them
+        let frame = lz4_frame(
+            BLOCK_CHECKSUM | INDEPENDENT_BLOCKS,
+            Some(data.len() as u64),
+            &blocks,
+        );
+        assert!(decompress(&frame, Compression::Lz4).is_err());
+    }
+
+    #[test]
+    fn lz4_stored_blocks() {
+        let data = random(3000, 2);
+        let blocks = [
+            stored(&data[..1000]),
+            compressed(lz4_flex::block::compress(&data[1000..2000])),
+            stored(&data[2000..]),
+        ];
+        for flags in [0, INDEPENDENT_BLOCKS] {
+            let frame = lz4_frame(flags, Some(data.len() as u64), &blocks);
+            let elf = decompress(&frame, Compression::Lz4).unwrap();
+            assert_eq!(elf.as_slice(), data);
+        }
+        // a linked block may refer to a stored one
+        let linked = compressed(lz4_flex::block::compress_with_dict(
+            &data[..1000],
+            &data[..1000],
+        ));
+        let frame = lz4_frame(0, Some(2000), &[stored(&data[..1000]), linked]);
+        let elf = decompress(&frame, Compression::Lz4).unwrap();
+        assert_eq!(elf.as_slice(), [&data[..1000], &data[..1000]].concat());
+    }
+
+    #[test]
+    fn lz4_content_size() {
+        let data = random(1000, 3);
+        let blocks = [stored(&data)];
+        assert_eq!(
+            decompress(&lz4_frame(0, None, &blocks), Compression::Lz4).err(),
+            Some(LoaderError::Compression("the frame has no content size"))
+        );
+        let frame = lz4_frame(0, Some(MAX_IMAGE_SIZE as u64 + 1), &blocks);
+        assert_eq!(
+            content_size(&frame, Compression::Lz4),
+            Err(LoaderError::Compression("the image is too large"))
+        );
+        // the blocks hold more than the frame declares
+        assert_eq!(
+            decompress(&lz4_frame(0, Some(999), &blocks), Compression::Lz4).err(),
+            Some(LoaderError::Compression("bad lz4 block"))
+        );
+        assert_eq!(
+            decompress(&lz4_frame(0, Some(1001), &blocks), Compression::Lz4).err(),
+            Some(LoaderError::Compression("the content size does not match"))
+        );
+        // the frame ends inside a block
+        let frame = lz4_frame(0, Some(1000), &blocks);
+        assert_eq!(
+            decompress(&frame[..frame.len() - 10], Compression::Lz4).err(),
+            Some(LoaderError::Compression("bad lz4 block"))
+        );
+    }
+}
@@ -1,5 +1,11 @@
 #![no_std]
 
+mod compress;
+mod manifest;
+mod reloc;
+mod sign;
+mod symbols;
+mod validate;
 mod vm;
 
 extern crate alloc;
@@ -14,18 +20,70 @@ use alloc::{
 };
 use core::{
     fmt::{Debug, Formatter},
+    mem::size_of,
     ops::Range,
 };
 
 use log::{debug, trace};
+pub use manifest::{DomainManifest, Version, MANIFEST_SECTION};
 use memory_addr::VirtAddr;
+pub use sign::{register_trusted_key, set_signature_policy, SignaturePolicy, SIGNATURE_MAGIC};
 use storage::StorageArg;
+pub use symbols::{Symbol, SymbolIndex};
 pub use vm::{DomainArea, DomainVmOps};
-use xmas_elf::{program::Type, sections::SectionData, ElfFile};
+use xmas_elf::{program::Type, ElfFile};
 
 use crate::vm::DomainMappingFlags;
 const FRAME_SIZE: usize = 4096;
-type Result<T> = core::result::Result<T, &'static str>;
+type Result<T> = core::result::Result<T, LoaderError>;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LoaderError {
+    /// The image does not start with the ELF magic.
+    NotElf,
+    /// A header or a table of the image is damaged.
+    Malformed(&'static str),
+    /// The image is not a 64-bit little-endian ELF.
+    UnsupportedClass,
+    /// The image is built for another machine.
+    WrongMachine,
+    /// The image is not a position independent shared object (`ET_DYN`).
+    NotDynamic,
+    /// The program header at `index` describes an invalid loadable segment.
+    BadSegment {
+        index: usize,
+        reason: &'static str,
+    },
+    /// The alignment of the segment is not a power of two or does not match its offset.
+    BadAlignment {
+        index: usize,
+    },
+    /// The entry point is not in an executable segment.
+    BadEntry(usize),
+    UnsupportedRelocation(u32),
+    BadRelocation(&'static str),
+    /// The symbol is neither defined by the domain nor exported by the kernel.
+    UnresolvedSymbol(String),
+    /// The image is not signed and unsigned domains are not allowed.
+    Unsigned,
+    /// The signature does not match the image or no trusted key signed it.
+    BadSignature,
+    /// The compressed image is damaged or not supported.
+    Compression(&'static str),
+    /// The `.domain_manifest` section is damaged.
+    BadManifest(&'static str),
+    /// A page would be both writable and executable.
+    WritableExecutable,
+    /// The kernel failed to change the mapping of the domain area.
+    Vm(&'static str),
+}
+
+impl From<&'static str> for LoaderError {
+    /// The errors of the ELF parser.
+    fn from(reason: &'static str) -> Self {
+        LoaderError::Malformed(reason)
+    }
+}
 
 pub struct DomainLoader<V: DomainVmOps> {
     entry_point: usize,
@@ -33,7 +91,13 @@ pub struct DomainLoader<V: DomainVmOps> {
     virt_start: usize,
     module_area: Option<Box<dyn DomainArea>>,
     ident: String,
-    text_section: Range<usize>,
+    /// The page-aligned ranges of the loaded segments with their permissions.
+    segments: Vec<(Range<usize>, DomainMappingFlags)>,
+    symbols: SymbolIndex,
+    /// The file decompressed by [`DomainLoader::manifest`], taken by the next load.
+    image: Option<compress::Decompressed>,
+    /// The manifest, `Some` once the image is parsed.
+    manifest: Option<Option<DomainManifest>>,
     _phantom: core::marker::PhantomData<V>,
 }
 
@@ -43,7 +107,7 @@ impl<V: DomainVmOps> Debug for DomainLoader<V> {
             .field("entry", &self.entry_point)
             .field("phy_start", &self.virt_start)
             .field("ident", &self.ident)
-            .field("text_section", &self.text_section)
+            .field("segments", &self.segments)
             .finish()
     }
 }
@@ -56,7 +120,10 @@ impl<V: DomainVmOps> Clone for DomainLoader<V> {
             virt_start: 0,
             ident: self.ident.to_string(),
             module_area: None,
-            text_section: self.text_section.clone(),
+            segments: self.segments.clone(),
+            symbols: SymbolIndex::default(),
+            image: None,
+            manifest: self.manifest.clone(),
             _phantom: core::marker::PhantomData,
         }
     }
@@ -70,14 +137,24 @@ impl<V: DomainVmOps> DomainLoader<V> {
             virt_start: 0,
             ident: ident.to_string(),
             module_area: None,
-            text_section: 0..0,
+            segments: Vec::new(),
+            symbols: SymbolIndex::default(),
+            image: None,
+            manifest: None,
             _phantom: core::marker::PhantomData,
         }
     }
 
-    /// Return the domain file info(name, size)
-    pub fn domain_file_info(&self) -> (String, usize) {
-        (self.ident.clone(), self.data.len())
+    /// Return the domain file info(name, size, compressed size). The size of a damaged
+    /// compressed image is 0.
+    pub fn domain_file_info(&self) -> (String, usize, Option<usize>) {
+        match compress::detect(&self.data) {
+            Some(compression) => {
+                let size = compress::content_size(&self.data, compression).unwrap_or(0);
+                (self.ident.clone(), size, Some(self.data.len()))
+            }
+            None => (self.ident.clone(), self.data.len(), None),
+        }
     }
 
     pub fn empty() -> Self {
@@ -112,11 +189,11 @@ impl<V: DomainVmOps> DomainLoader<V> {
     }
 
     fn load_program(&mut self, elf: &ElfFile) -> Result<()> {
+        self.segments.clear();
         elf.program_iter()
             .filter(|ph| ph.get_type() == Ok(Type::Load))
             .for_each(|ph| {
                 let start_vaddr = ph.virtual_addr() as usize + self.virt_start;
-                let end_vaddr = start_vaddr + ph.mem_size() as usize;
                 let mut permission = DomainMappingFlags::empty();
                 let ph_flags = ph.flags();
                 if ph_flags.is_read() {
@@ -128,8 +205,11 @@ impl<V: DomainVmOps> DomainLoader<V> {
                 if ph_flags.is_execute() {
                     permission |= DomainMappingFlags::EXECUTE;
                 }
-                let vaddr = VirtAddr::from(start_vaddr).align_down_4k().as_usize();
-                let end_vaddr = VirtAddr::from(end_vaddr).align_up_4k().as_usize();
+                let vaddr = VirtAddr::from(ph.virtual_addr() as usize).align_down_4k();
+                let end =
+                    VirtAddr::from((ph.virtual_addr() + ph.mem_size()) as usize).align_up_4k();
+                let vaddr = self.virt_start + vaddr.as_usize();
+                let end_vaddr = self.virt_start + end.as_usize();
                 // log::error!(
                 //     "map range: [{:#x}-{:#x}], memsize:{:#x}, perm:{:?}",
                 //     vaddr,
@@ -150,108 +230,184 @@ impl<V: DomainVmOps> DomainLoader<V> {
                 //     copy_start,
                 //     copy_start + data_len
                 // );
-                if permission.contains(DomainMappingFlags::EXECUTE) {
-                    self.text_section = vaddr..end_vaddr;
-                }
+                self.segments.push((vaddr..end_vaddr, permission));
             });
         Ok(())
     }
-    fn relocate_dyn(&self, elf: &ElfFile) -> Result<()> {
-        if let Ok(res) = relocate_dyn(elf, self.virt_start) {
-            trace!("Relocate_dyn {} entries", res.len());
-            res.into_iter().for_each(|kv| {
-                trace!("relocate: {:#x} -> {:#x}", kv.0, kv.1);
-                let addr = kv.0;
-                unsafe { (addr as *mut usize).write(kv.1) }
-            });
-            trace!("Relocate_dyn done");
+
+    /// Apply the permissions of the segments once the domain is relocated. A page shared
+    /// by two segments gets the permissions of both, and the RELRO pages become read-only.
+    fn protect(&self, elf: &ElfFile) -> Result<()> {
+        let area_size = self.module_area.as_ref().unwrap().as_slice().len();
+        let mut pages = vec![DomainMappingFlags::empty(); area_size / FRAME_SIZE];
+        for (range, permission) in self.segments.iter() {
+            let start = (range.start - self.virt_start) / FRAME_SIZE;
+            let end = (range.end - self.virt_start) / FRAME_SIZE;
+            pages[start..end]
+                .iter_mut()
+                .for_each(|page| *page |= *permission);
+        }
+        for ph in elf
+            .program_iter()
+            .filter(|ph| ph.get_type() == Ok(Type::GnuRelro))
+        {
+            let start = VirtAddr::from(ph.virtual_addr() as usize).align_down_4k();
+            let end = VirtAddr::from((ph.virtual_addr() + ph.mem_size()) as usize).align_down_4k();
+            pages
+                .get_mut(start.as_usize() / FRAME_SIZE..end.as_usize() / FRAME_SIZE)
+                .ok_or(LoaderError::Malformed("bad RELRO segment"))?
+                .iter_mut()
+                .for_each(|page| page.remove(DomainMappingFlags::WRITE));
         }
+        let mut start = 0;
+        while start < pages.len() {
+            let permission = pages[start];
+            let end = pages[start..]
+                .iter()
+                .position(|page| *page != permission)
+                .map_or(pages.len(), |n| start + n);
+            let vaddr = self.virt_start + start * FRAME_SIZE;
+            trace!(
+                "protect range: [{:#x}-{:#x}], perm:{:?}",
+                vaddr,
+                self.virt_start + end * FRAME_SIZE,
+                permission
+            );
+            if permission.contains(DomainMappingFlags::WRITE | DomainMappingFlags::EXECUTE) {
+                return Err(LoaderError::WritableExecutable);
+            } else if permission.contains(DomainMappingFlags::EXECUTE) {
+                V::set_memory_x(vaddr, end - start).map_err(LoaderError::Vm)?;
+            } else if !permission.contains(DomainMappingFlags::WRITE) {
+                V::set_memory_ro(vaddr, end - start).map_err(LoaderError::Vm)?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+    fn relocate_dyn(&self, elf: &ElfFile) -> Result<()> {
+        let module_slice = self.module_area.as_ref().unwrap().as_mut_slice();
+        let res = reloc::relocate_dyn(elf, self.virt_start, module_slice.len(), V::kernel_symbol)?;
+        trace!("Relocate_dyn {} entries", res.len());
+        res.into_iter().for_each(|(offset, value)| {
+            trace!("relocate: {:#x} -> {:#x}", self.virt_start + offset, value);
+            module_slice[offset..offset + size_of::<usize>()].copy_from_slice(&value.to_ne_bytes());
+        });
+        trace!("Relocate_dyn done");
         Ok(())
     }
 
     pub fn load(&mut self) -> Result<()> {
         let data = self.data.clone();
-        let elf_binary = data.as_slice();
-        const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
-        if elf_binary[0..4] != ELF_MAGIC {
-            return Err("not a elf file");
+        // the decompressed file is dropped once the segments are copied
+        let image = match self.image.take() {
+            Some(image) => ElfImage::Decompressed(image),
+            None => open_image(data.as_slice(), &self.ident)?,
+        };
+        let (elf, area_size) = parse_elf(image.as_slice())?;
+        if self.manifest.is_none() {
+            // a damaged manifest is reported by `manifest`, not by the load
+            self.manifest = manifest::parse(&elf).ok();
         }
-        debug!("Domain address:{:p}", elf_binary.as_ptr());
-        let elf = ElfFile::new(elf_binary)?;
-        debug!("Domain type:{:?}", elf.header.pt2.type_().as_type());
-        let end_paddr = elf
-            .program_iter()
-            .filter(|ph| ph.get_type() == Ok(Type::Load))
-            .last()
-            .map(|x| x.virtual_addr() as usize + x.mem_size() as usize)
-            .unwrap();
-        let end_paddr = VirtAddr::from(end_paddr).align_up(FRAME_SIZE);
         // alloc free page to map elf
-        let module_area = V::map_domain_area(end_paddr.as_usize());
+        let module_area = V::map_domain_area(area_size);
         let region_start = module_area.start_virtual_address().as_usize();
         // log::error!(
         //     "region range:{:#x}-{:#x}",
         //     region_start,
-        //     region_start + end_paddr.as_usize()
+        //     region_start + area_size
         // );
         self.virt_start = region_start;
         self.module_area = Some(module_area);
         self.load_program(&elf)?;
         self.relocate_dyn(&elf)?;
-        // update text section permission
-        let text_pages = (self.text_section.end - self.text_section.start) / FRAME_SIZE;
-        V::set_memory_x(self.text_section.start, text_pages)?;
-        // log::error!(
-        //     "set_memory_x range: {:#x}-{:#x}",
-        //     self.text_section.start,
-        //     self.text_section.end
-        // );
+        // the area is writable until the domain is relocated
+        self.protect(&elf)?;
+        self.symbols = SymbolIndex::new(&elf, region_start);
+        debug!(
+            "Domain [{}] has {} functions",
+            self.ident,
+            self.symbols.len()
+        );
         let entry = elf.header.pt2.entry_point() as usize + region_start;
         // log::error!("entry: {:#x}", entry);
         self.entry_point = entry;
         Ok(())
     }
-}
 
-impl<V: DomainVmOps> Drop for DomainLoader<V> {
-    fn drop(&mut self) {
-        info!("drop domain loader [{}]", self.ident);
-        if let Some(module_area) = self.module_area.take() {
-            V::unmap_domain_area(module_area)
+    /// The functions of the loaded domain.
+    pub fn symbols(&self) -> &SymbolIndex {
+        &self.symbols
+    }
+
+    /// Find the function of the loaded domain containing the address.
+    pub fn symbolize(&self, addr: usize) -> Option<Symbol<'_>> {
+        self.symbols.lookup(addr)
+    }
+
+    /// Read the manifest of the domain without loading it, `None` if it has no manifest.
+    /// A compressed image is decompressed once, the file is kept for the next load.
+    pub fn manifest(&mut self) -> Result<Option<DomainManifest>> {
+        if let Some(manifest) = &self.manifest {
+            return Ok(manifest.clone());
+        }
+        let data = self.data.clone();
+        let image = open_image(data.as_slice(), &self.ident)?;
+        let (elf, _) = parse_elf(image.as_slice())?;
+        let manifest = manifest::parse(&elf)?;
+        self.manifest = Some(manifest.clone());
+        if let ElfImage::Decompressed(image) = image {
+            self.image = Some(image);
         }
+        Ok(manifest)
     }
 }
 
-fn relocate_dyn(elf: &ElfFile, region_start: usize) -> Result<Vec<(usize, usize)>> {
-    let data = elf
-        .find_section_by_name(".rela.dyn")
-        .map(|h| h.get_data(elf).unwrap())
-        .ok_or("corrupted .rela.dyn")?;
-    let entries = match data {
-        SectionData::Rela64(entries) => entries,
-        _ => return Err("bad .rela.dyn"),
-    };
-    let mut res = vec![];
-    for entry in entries.iter() {
-        match entry.get_type() {
-            RELATIVE => {
-                let value = region_start + entry.get_addend() as usize;
-                let addr = region_start + entry.get_offset() as usize;
-                res.push((addr, value))
-            }
-            t => unimplemented!("unknown type: {}", t),
+/// The ELF file of a domain image.
+enum ElfImage<'a> {
+    Plain(&'a [u8]),
+    Decompressed(compress::Decompressed),
+}
+
+impl ElfImage<'_> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            ElfImage::Plain(data) => data,
+            ElfImage::Decompressed(data) => data.as_slice(),
         }
     }
-    Ok(res)
 }
 
-#[cfg(target_arch = "riscv64")]
-const R_RISCV_RELATIVE: u32 = 3;
-#[cfg(target_arch = "x86_64")]
-const R_X86_64_RELATIVE: u32 = 8;
+/// Check the signature of the image and decompress it, nothing is parsed before the
+/// image is trusted.
+fn open_image<'a>(data: &'a [u8], ident: &str) -> Result<ElfImage<'a>> {
+    let image = sign::verify(data, ident)?;
+    match compress::detect(image) {
+        Some(compression) => Ok(ElfImage::Decompressed(compress::decompress(
+            image,
+            compression,
+        )?)),
+        None => Ok(ElfImage::Plain(image)),
+    }
+}
 
-#[cfg(target_arch = "riscv64")]
-const RELATIVE: u32 = R_RISCV_RELATIVE;
+/// Parse and validate the ELF file, return it with the size of its domain area.
+fn parse_elf(elf_binary: &[u8]) -> Result<(ElfFile<'_>, usize)> {
+    const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+    if !elf_binary.starts_with(&ELF_MAGIC) {
+        return Err(LoaderError::NotElf);
+    }
+    debug!("Domain address:{:p}", elf_binary.as_ptr());
+    let elf = ElfFile::new(elf_binary)?;
+    debug!("Domain type:{:?}", elf.header.pt2.type_().as_type());
+    let area_size = validate::validate(&elf)?;
+    Ok((elf, area_size))
+}
 
-#[cfg(target_arch = "x86_64")]
-const RELATIVE: u32 = R_X86_64_RELATIVE;
+impl<V: DomainVmOps> Drop for DomainLoader<V> {
+    fn drop(&mut self) {
+        info!("drop domain loader [{}]", self.ident);
+        if let Some(module_area) = self.module_area.take() {
+            V::unmap_domain_area(module_area)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! The manifest of a domain, written to the `.domain_manifest` section by
+//! `#[domain_main(...)]`.
+//!
+//! The section is little-endian:
+//!
+//! | offset | field                                          |
+//! |--------|------------------------------------------------|
+//! | 0      | magic `DOMMANIF`                               |
+//! | 8      | format version, u32                            |
+//! | 12     | `DomainTypeRaw`, u32                           |
+//! | 16     | version major, minor, patch, u16 each          |
+//! | 24     | interface fingerprint, u64                     |
+//! | 32     | heap size in bytes, u64                        |
+//! | 40     | pages, u64                                     |
+//! | 48     | name, then the number of dependencies as u16   |
+//! |        | and the dependency names                       |
+//!
+//! A string is its length as u16 followed by its UTF-8 bytes.
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::{Display, Formatter};
+
+use xmas_elf::ElfFile;
+
+use crate::{LoaderError, Result};
+
+pub const MANIFEST_SECTION: &str = ".domain_manifest";
+const MANIFEST_MAGIC: [u8; 8] = *b"DOMMANIF";
+const MANIFEST_FORMAT: u32 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DomainManifest {
+    pub name: String,
+    /// The `DomainTypeRaw` of the domain.
+    pub ty: u8,
+    pub version: Version,
+    /// The fingerprint of the interface the domain is built against, 0 if not declared.
+    pub interface_fingerprint: u64,
+    /// The names of the domains it uses at init.
+    pub dependencies: Vec<String>,
+    /// The heap it asks for in bytes.
+    pub heap_size: u64,
+    pub pages: u64,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(LoaderError::BadManifest("the manifest is truncated"));
+        }
+        let (data, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?)
+            .map(|s| s.to_string())
+            .map_err(|_| LoaderError::BadManifest("a name is not UTF-8"))
+    }
+}
+
+/// Read the manifest of a validated image, `None` if it has no manifest.
+pub(crate) fn parse(elf: &ElfFile) -> Result<Option<DomainManifest>> {
+    let section = match elf.find_section_by_name(MANIFEST_SECTION) {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    read(section.raw_data(elf)).map(Some)
+}
+
+/// Read the manifest written by `#[domain_main(...)]` from the data of its section.
+fn read(data: &[u8]) -> Result<DomainManifest> {
+    let mut reader = Reader { data };
+    if reader.take(MANIFEST_MAGIC.len())? != MANIFEST_MAGIC {
+        return Err(LoaderError::BadManifest("bad magic"));
+    }
+    if reader.u32()? != MANIFEST_FORMAT {
+        return Err(LoaderError::BadManifest("unknown format"));
+    }
+    let ty =
+        u8::try_from(reader.u32()?).map_err(|_| LoaderError::BadManifest("bad domain type"))?;
+    let version = Version {
+        major: reader.u16()?,
+        minor: reader.u16()?,
+        patch: reader.u16()?,
+    };
+    reader.u16()?;
+    let interface_fingerprint = reader.u64()?;
+    let heap_size = reader.u64()?;
+    let pages = reader.u64()?;
+    let name = reader.str()?;
+    let dependencies = (0..reader.u16()?)
+        .map(|_| reader.str())
+        .collect::<Result<Vec<_>>>()?;
+    Ok(DomainManifest {
+        name,
+        ty,
+        version,
+        interface_fingerprint,
+        dependencies,
+        heap_size,
+        pages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn push_str(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    /// The section `#[domain_main(...)]` emits.
+    fn section(manifest: &DomainManifest) -> Vec<u8> {
+        let mut buf = MANIFEST_MAGIC.to_vec();
+        buf.extend_from_slice(&MANIFEST_FORMAT.to_le_bytes());
+        buf.extend_from_slice(&(manifest.ty as u32).to_le_bytes());
+        let version = manifest.version;
+        for part in [version.major, version.minor, version.patch, 0] {
+            buf.extend_from_slice(&part.to_le_bytes());
+        }
+        buf.extend_from_slice(&manifest.interface_fingerprint.to_le_bytes());
+        buf.extend_from_slice(&manifest.heap_size.to_le_bytes());
+        buf.extend_from_slice(&manifest.pages.to_le_bytes());
+        assert_eq!(buf.len(), 48);
+        push_str(&mut buf, &manifest.name);
+        buf.extend_from_slice(&(manifest.dependencies.len() as u16).to_le_bytes());
+        for dependency in &manifest.dependencies {
+            push_str(&mut buf, dependency);
+        }
+        buf
+    }
+
+    fn manifest(dependencies: &[&str]) -> DomainManifest {
+        DomainManifest {
+            name: "cache_blk".to_string(),
+            ty: 3,
+            version: Version {
+                major: 1,
+                minor: 20,
+                patch: 300,
+            },
+            interface_fingerprint: 0x0123_4567_89ab_cdef,
+            dependencies: dependencies.iter().map(|name| name.to_string()).collect(),
+            heap_size: 1 << 20,
+            pages: 16,
+        }
+    }
+
+    #[test]
+    fn read_emitted_layout() {
+        for dependencies in [&[][..], &["virtio_mmio_block"], &["blk", "log", "plic"]] {
+            let manifest = manifest(dependencies);
+            assert_eq!(read(&section(&manifest)), Ok(manifest));
+        }
+    }
+
+    #[test]
+    fn read_truncated() {
+        let data = section(&manifest(&["blk", "log"]));
+        for len in 0..data.len() {
+            assert_eq!(
+                read(&data[..len]),
+                Err(LoaderError::BadManifest("the manifest is truncated"))
+            );
+        }
+    }
+
+    #[test]
+    fn read_damaged() {
+        let data = section(&manifest(&["blk"]));
+        let damaged = |offset: usize, bytes: &[u8]| {
+            let mut data = data.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            read(&data)
+        };
+        assert_eq!(
+            damaged(0, b"DOMMANIX"),
+            Err(LoaderError::BadManifest("bad magic"))
+        );
+        assert_eq!(
+            damaged(8, &2u32.to_le_bytes()),
+            Err(LoaderError::BadManifest("unknown format"))
+        );
+        assert_eq!(
+            damaged(12, &256u32.to_le_bytes()),
+            Err(LoaderError::BadManifest("bad domain type"))
+        );
+        // the first byte of the name
+        assert_eq!(
+            damaged(50, &[0xff]),
+            Err(LoaderError::BadManifest("a name is not UTF-8"))
+        );
+        // more dependencies than the section holds
+        let count = 50 + "cache_blk".len();
+        assert_eq!(
+            damaged(count, &2u16.to_le_bytes()),
+            Err(LoaderError::BadManifest("the manifest is truncated"))
+        );
+        assert_eq!(read(&[0; 8]), Err(LoaderError::BadManifest("bad magic")));
+    }
+}
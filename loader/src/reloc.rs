@@ -0,0 +1,158 @@
+//! Dynamic relocations of a domain, applied after its segments are copied to the domain
+//! area.
+use alloc::{string::ToString, vec, vec::Vec};
+use core::mem::size_of;
+
+use xmas_elf::{
+    sections::{SectionData, SHN_ABS, SHN_UNDEF},
+    symbol_table::{Binding, DynEntry64, Entry},
+    ElfFile,
+};
+
+use crate::{LoaderError, Result};
+
+/// The sections holding the dynamic relocations, `.rela.plt` is emitted for calls through
+/// the PLT.
+const RELA_SECTIONS: [&str; 2] = [".rela.dyn", ".rela.plt"];
+
+/// How the value of a relocation is computed, `B` is the load address of the domain, `S`
+/// the address of the symbol and `A` the addend.
+enum RelocKind {
+    None,
+    /// B + A
+    Relative,
+    /// S + A
+    Absolute,
+    /// S
+    Symbol,
+}
+
+#[cfg(target_arch = "riscv64")]
+mod arch {
+    pub const R_RISCV_NONE: u32 = 0;
+    pub const R_RISCV_64: u32 = 2;
+    pub const R_RISCV_RELATIVE: u32 = 3;
+    pub const R_RISCV_JUMP_SLOT: u32 = 5;
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    pub const R_X86_64_NONE: u32 = 0;
+    pub const R_X86_64_64: u32 = 1;
+    pub const R_X86_64_GLOB_DAT: u32 = 6;
+    pub const R_X86_64_JUMP_SLOT: u32 = 7;
+    pub const R_X86_64_RELATIVE: u32 = 8;
+}
+
+/// RISC-V fills the GOT with `R_RISCV_64`, it has no `GLOB_DAT`.
+#[cfg(target_arch = "riscv64")]
+fn reloc_kind(ty: u32) -> Option<RelocKind> {
+    use arch::*;
+    match ty {
+        R_RISCV_NONE => Some(RelocKind::None),
+        R_RISCV_64 => Some(RelocKind::Absolute),
+        R_RISCV_RELATIVE => Some(RelocKind::Relative),
+        R_RISCV_JUMP_SLOT => Some(RelocKind::Symbol),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn reloc_kind(ty: u32) -> Option<RelocKind> {
+    use arch::*;
+    match ty {
+        R_X86_64_NONE => Some(RelocKind::None),
+        R_X86_64_64 => Some(RelocKind::Absolute),
+        R_X86_64_RELATIVE => Some(RelocKind::Relative),
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => Some(RelocKind::Symbol),
+        _ => None,
+    }
+}
+
+/// Resolve the symbol of a relocation. Symbols not defined by the domain are looked up
+/// by `resolve` in the symbols exported by the kernel, an undefined weak symbol is zero.
+fn symbol_address<F>(
+    elf: &ElfFile,
+    symbols: &[DynEntry64],
+    index: u32,
+    region_start: usize,
+    resolve: &F,
+) -> Result<usize>
+where
+    F: Fn(&str) -> Option<usize>,
+{
+    if index == 0 {
+        return Ok(0);
+    }
+    let symbol = symbols
+        .get(index as usize)
+        .ok_or(LoaderError::BadRelocation("bad symbol index"))?;
+    match symbol.shndx() {
+        SHN_UNDEF => {}
+        SHN_ABS => return Ok(symbol.value() as usize),
+        _ => return Ok(region_start + symbol.value() as usize),
+    }
+    let name = symbol.get_name(elf)?;
+    match resolve(name) {
+        Some(addr) => Ok(addr),
+        None if symbol.get_binding() == Ok(Binding::Weak) => Ok(0),
+        None => Err(LoaderError::UnresolvedSymbol(name.to_string())),
+    }
+}
+
+/// Compute the dynamic relocations of a domain loaded at `region_start`. Return the
+/// offsets in the domain area with the values to write there.
+pub(crate) fn relocate_dyn<F>(
+    elf: &ElfFile,
+    region_start: usize,
+    area_size: usize,
+    resolve: F,
+) -> Result<Vec<(usize, usize)>>
+where
+    F: Fn(&str) -> Option<usize>,
+{
+    let symbols = match elf.find_section_by_name(".dynsym") {
+        Some(header) => match header.get_data(elf)? {
+            SectionData::DynSymbolTable64(symbols) => symbols,
+            _ => return Err(LoaderError::Malformed("bad .dynsym")),
+        },
+        None => &[],
+    };
+    let mut res = vec![];
+    for name in RELA_SECTIONS {
+        let header = match elf.find_section_by_name(name) {
+            Some(header) => header,
+            None => continue,
+        };
+        let entries = match header.get_data(elf)? {
+            SectionData::Rela64(entries) => entries,
+            _ => return Err(LoaderError::Malformed("bad relocation section")),
+        };
+        for entry in entries.iter() {
+            let ty = entry.get_type();
+            let kind = reloc_kind(ty).ok_or(LoaderError::UnsupportedRelocation(ty))?;
+            let addend = entry.get_addend() as usize;
+            let value = match kind {
+                RelocKind::None => continue,
+                RelocKind::Relative => region_start.wrapping_add(addend),
+                RelocKind::Absolute | RelocKind::Symbol => {
+                    let index = entry.get_symbol_table_index();
+                    let symbol = symbol_address(elf, symbols, index, region_start, &resolve)?;
+                    match kind {
+                        RelocKind::Absolute => symbol.wrapping_add(addend),
+                        _ => symbol,
+                    }
+                }
+            };
+            let offset = entry.get_offset() as usize;
+            if offset
+                .checked_add(size_of::<usize>())
+                .map_or(true, |end| end > area_size)
+            {
+                return Err(LoaderError::BadRelocation("out of the domain area"));
+            }
+            res.push((offset, value));
+        }
+    }
+    Ok(res)
+}
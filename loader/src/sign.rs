@@ -0,0 +1,63 @@
+//! Signatures of domain images.
+//!
+//! A signed image is the ELF file followed by a trailer: the Ed25519 signature of the ELF
+//! file and [`SIGNATURE_MAGIC`]. The ELF parser ignores the trailer, so a signed image is
+//! still a valid ELF file.
+use alloc::vec::Vec;
+
+use ed25519_compact::{PublicKey, Signature};
+use spin::Mutex;
+
+use crate::{LoaderError, Result};
+
+/// The magic at the end of a signed image.
+pub const SIGNATURE_MAGIC: [u8; 8] = *b"DOMSIGN1";
+const TRAILER_SIZE: usize = Signature::BYTES + SIGNATURE_MAGIC.len();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SignaturePolicy {
+    /// Every domain must be signed by a trusted key.
+    Required,
+    /// Unsigned domains are loaded with a warning, for development. A signed domain must
+    /// still have a valid signature.
+    AllowUnsigned,
+}
+
+static TRUSTED_KEYS: Mutex<Vec<PublicKey>> = Mutex::new(Vec::new());
+static POLICY: Mutex<SignaturePolicy> = Mutex::new(SignaturePolicy::Required);
+
+/// Trust the domains signed by the Ed25519 public key. The kernel registers its built-in
+/// keys and the keys given at boot before loading any domain.
+pub fn register_trusted_key(key: [u8; PublicKey::BYTES]) {
+    TRUSTED_KEYS.lock().push(PublicKey::new(key));
+}
+
+pub fn set_signature_policy(policy: SignaturePolicy) {
+    info!("domain signature policy: {:?}", policy);
+    *POLICY.lock() = policy;
+}
+
+/// Check the signature of the image and return the ELF file without the trailer.
+pub(crate) fn verify<'a>(image: &'a [u8], ident: &str) -> Result<&'a [u8]> {
+    let (elf, trailer) = match image.len().checked_sub(TRAILER_SIZE) {
+        Some(len) if image.ends_with(&SIGNATURE_MAGIC) => image.split_at(len),
+        _ => {
+            return match *POLICY.lock() {
+                SignaturePolicy::Required => Err(LoaderError::Unsigned),
+                SignaturePolicy::AllowUnsigned => {
+                    warn!("load unsigned domain [{}]", ident);
+                    Ok(image)
+                }
+            };
+        }
+    };
+    let signature = Signature::from_slice(&trailer[..Signature::BYTES])
+        .map_err(|_| LoaderError::BadSignature)?;
+    // verify without holding the lock, other domains may be loaded meanwhile
+    let keys = TRUSTED_KEYS.lock().clone();
+    if keys.iter().any(|key| key.verify(elf, &signature).is_ok()) {
+        Ok(elf)
+    } else {
+        Err(LoaderError::BadSignature)
+    }
+}
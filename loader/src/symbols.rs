@@ -0,0 +1,142 @@
+//! The functions of a loaded domain, kept to symbolize the addresses of its backtraces.
+//!
+//! The index is built from `.symtab`, or `.dynsym` if the domain is stripped. Names are
+//! demangled once when the domain is loaded and stored in one string, so the index costs
+//! 12 bytes per function plus its name.
+use alloc::{string::String, vec::Vec};
+use core::fmt::{Display, Formatter, Write};
+
+use xmas_elf::{
+    sections::{SectionData, ShType, SHN_UNDEF},
+    symbol_table::{Entry, Type},
+    ElfFile,
+};
+
+/// A function, its offset and the name are relative to the start of the domain area and
+/// of the names.
+#[derive(Debug, Copy, Clone)]
+struct Function {
+    start: u32,
+    size: u32,
+    name: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    /// The load address of the domain.
+    base: usize,
+    /// Sorted by start.
+    functions: Vec<Function>,
+    names: String,
+}
+
+/// The function containing an address.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// The offset of the address in the function.
+    pub offset: usize,
+}
+
+impl Display for Symbol<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Read the string at `index` of the table, `None` if it is damaged.
+fn symbol_name(strings: &[u8], index: u32) -> Option<&str> {
+    let data = strings.get(index as usize..)?;
+    let len = data.iter().position(|byte| *byte == 0)?;
+    core::str::from_utf8(&data[..len]).ok()
+}
+
+impl SymbolIndex {
+    /// Index the functions of a validated image loaded at `base`. Symbols with a damaged
+    /// name or outside of the first 4 GiB of the area are left out.
+    pub(crate) fn new(elf: &ElfFile, base: usize) -> Self {
+        let mut index = SymbolIndex {
+            base,
+            ..Default::default()
+        };
+        let (symbols, strings) = match [(".symtab", ".strtab"), (".dynsym", ".dynstr")]
+            .iter()
+            .find_map(|(symbols, strings)| {
+                let symbols = elf.find_section_by_name(symbols)?;
+                let strings = elf
+                    .find_section_by_name(strings)
+                    .filter(|header| header.get_type() == Ok(ShType::StrTab))?;
+                Some((symbols.get_data(elf).ok()?, strings.raw_data(elf)))
+            }) {
+            Some(tables) => tables,
+            None => return index,
+        };
+        match symbols {
+            SectionData::SymbolTable64(symbols) => index.add(symbols, strings),
+            SectionData::DynSymbolTable64(symbols) => index.add(symbols, strings),
+            _ => return index,
+        }
+        index.functions.sort_by_key(|function| function.start);
+        // aliases of a function share its address, keep the first one
+        index.functions.dedup_by_key(|function| function.start);
+        index
+    }
+
+    /// Add the functions of the symbol table, `strings` is its string table.
+    fn add<E: Entry>(&mut self, symbols: &[E], strings: &[u8]) {
+        for symbol in symbols.iter() {
+            if symbol.get_type() != Ok(Type::Func)
+                || symbol.shndx() == SHN_UNDEF
+                || symbol.size() == 0
+            {
+                continue;
+            }
+            let (start, size, name) = match (
+                u32::try_from(symbol.value()),
+                u32::try_from(symbol.size()),
+                symbol_name(strings, symbol.name()),
+            ) {
+                (Ok(start), Ok(size), Some(name)) => (start, size, name),
+                _ => continue,
+            };
+            let offset = match u32::try_from(self.names.len()) {
+                Ok(offset) => offset,
+                Err(_) => break,
+            };
+            // `{:#}` leaves out the hash of Rust symbols
+            let _ = write!(self.names, "{:#}\0", rustc_demangle::demangle(name));
+            self.functions.push(Function {
+                start,
+                size,
+                name: offset,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Find the function containing the address.
+    pub fn lookup(&self, addr: usize) -> Option<Symbol<'_>> {
+        let offset = addr.checked_sub(self.base)?;
+        let index = self
+            .functions
+            .partition_point(|function| function.start as usize <= offset)
+            .checked_sub(1)?;
+        let function = self.functions[index];
+        let offset = offset - function.start as usize;
+        if offset >= function.size as usize {
+            return None;
+        }
+        let name = &self.names[function.name as usize..];
+        Some(Symbol {
+            name: &name[..name.find('\0').unwrap_or(name.len())],
+            offset,
+        })
+    }
+}
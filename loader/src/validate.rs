@@ -0,0 +1,191 @@
+//! Checks of a domain image before it is loaded, so a damaged file fails with an error
+//! instead of a panic in the ELF parser.
+use core::mem::{align_of, size_of};
+
+use xmas_elf::{
+    header::{Class, Data, Machine, Type as ElfType},
+    program::{ProgramHeader64, Type},
+    sections::{SectionData, SectionHeader_, ShType, SHN_LORESERVE},
+    symbol_table::{DynEntry64, Entry},
+    ElfFile, P64,
+};
+
+use crate::{LoaderError, Result, FRAME_SIZE};
+
+#[cfg(target_arch = "riscv64")]
+const MACHINE: Machine = Machine::RISC_V;
+#[cfg(target_arch = "x86_64")]
+const MACHINE: Machine = Machine::X86_64;
+
+/// The size of a `Rela64` entry.
+const RELA_SIZE: u64 = 24;
+/// The size of a `Dynamic64` entry.
+const DYNAMIC_SIZE: u64 = 16;
+/// The largest area a domain may reserve, the whole area is mapped before the segments
+/// are copied.
+const MAX_AREA_SIZE: u64 = 1 << 30;
+
+/// Check that `len` bytes at `offset` are in the image.
+fn in_image(elf: &ElfFile, offset: u64, len: u64) -> bool {
+    offset
+        .checked_add(len)
+        .map_or(false, |end| end <= elf.input.len() as u64)
+}
+
+/// Check that the string at `index` of the table is terminated and valid UTF-8.
+fn valid_str(table: &[u8], index: u32) -> bool {
+    let data = match table.get(index as usize..) {
+        Some(data) => data,
+        None => return false,
+    };
+    match data.iter().position(|byte| *byte == 0) {
+        Some(len) => core::str::from_utf8(&data[..len]).is_ok(),
+        None => false,
+    }
+}
+
+fn check_header(elf: &ElfFile) -> Result<()> {
+    if elf.input.as_ptr() as usize % align_of::<u64>() != 0 {
+        return Err(LoaderError::Malformed("the image is not aligned"));
+    }
+    if elf.header.pt1.class() != Class::SixtyFour || elf.header.pt1.data() != Data::LittleEndian {
+        return Err(LoaderError::UnsupportedClass);
+    }
+    let machine = elf.header.pt2.machine().as_machine();
+    if machine != MACHINE {
+        error!("the domain is built for {:?}", machine);
+        return Err(LoaderError::WrongMachine);
+    }
+    if elf.header.pt2.type_().as_type() != ElfType::SharedObject {
+        return Err(LoaderError::NotDynamic);
+    }
+    let pt2 = &elf.header.pt2;
+    if pt2.ph_entry_size() as usize != size_of::<ProgramHeader64>()
+        || pt2.ph_offset() % 8 != 0
+        || !in_image(
+            elf,
+            pt2.ph_offset(),
+            pt2.ph_count() as u64 * pt2.ph_entry_size() as u64,
+        )
+    {
+        return Err(LoaderError::Malformed("bad program header table"));
+    }
+    if pt2.sh_count() != 0
+        && (pt2.sh_entry_size() as usize != size_of::<SectionHeader_<P64>>()
+            || pt2.sh_offset() % 8 != 0
+            || pt2.sh_count() >= SHN_LORESERVE
+            || pt2.sh_str_index() >= pt2.sh_count()
+            || !in_image(
+                elf,
+                pt2.sh_offset(),
+                pt2.sh_count() as u64 * pt2.sh_entry_size() as u64,
+            ))
+    {
+        return Err(LoaderError::Malformed("bad section header table"));
+    }
+    Ok(())
+}
+
+/// Check the loadable segments and return the size of the domain area.
+fn check_segments(elf: &ElfFile) -> Result<usize> {
+    let mut area_end = 0;
+    let mut loads = 0;
+    for (index, ph) in elf.program_iter().enumerate() {
+        if ph.get_type() != Ok(Type::Load) {
+            continue;
+        }
+        let bad_segment = |reason| LoaderError::BadSegment { index, reason };
+        if !in_image(elf, ph.offset(), ph.file_size()) {
+            return Err(bad_segment("the data is outside of the image"));
+        }
+        if ph.file_size() > ph.mem_size() {
+            return Err(bad_segment("the data is larger than the segment"));
+        }
+        let align = ph.align();
+        if align > 1
+            && (!align.is_power_of_two() || ph.virtual_addr() % align != ph.offset() % align)
+        {
+            return Err(LoaderError::BadAlignment { index });
+        }
+        let end = ph
+            .virtual_addr()
+            .checked_add(ph.mem_size())
+            .filter(|end| *end <= MAX_AREA_SIZE)
+            .ok_or(bad_segment("the segment is too large"))?;
+        // segments are sorted by address and must not overlap
+        if ph.virtual_addr() < area_end {
+            return Err(bad_segment("the segment overlaps the previous one"));
+        }
+        area_end = end;
+        loads += 1;
+    }
+    if loads == 0 {
+        return Err(LoaderError::Malformed("no loadable segment"));
+    }
+    let entry = elf.header.pt2.entry_point();
+    let executable = elf.program_iter().any(|ph| {
+        ph.get_type() == Ok(Type::Load)
+            && ph.flags().is_execute()
+            && (ph.virtual_addr()..ph.virtual_addr() + ph.mem_size()).contains(&entry)
+    });
+    if !executable {
+        return Err(LoaderError::BadEntry(entry as usize));
+    }
+    Ok((area_end as usize).next_multiple_of(FRAME_SIZE))
+}
+
+/// Check the sections read by the loader: the names, the relocations and the dynamic
+/// symbols.
+fn check_sections(elf: &ElfFile) -> Result<()> {
+    if elf.header.pt2.sh_count() == 0 {
+        return Ok(());
+    }
+    let shstr = elf.section_header(elf.header.pt2.sh_str_index())?;
+    if shstr.get_type() != Ok(ShType::StrTab) || !in_image(elf, shstr.offset(), shstr.size()) {
+        return Err(LoaderError::Malformed("bad section name table"));
+    }
+    let names = shstr.raw_data(elf);
+    for sh in elf.section_iter() {
+        if !valid_str(names, sh.name()) {
+            return Err(LoaderError::Malformed("bad section name"));
+        }
+        let entry_size = match sh.get_type() {
+            Ok(ShType::Null) | Ok(ShType::NoBits) => continue,
+            Ok(ShType::Rela) => RELA_SIZE,
+            Ok(ShType::DynSym) | Ok(ShType::SymTab) => size_of::<DynEntry64>() as u64,
+            Ok(ShType::Dynamic) => DYNAMIC_SIZE,
+            _ => 1,
+        };
+        if !in_image(elf, sh.offset(), sh.size())
+            || (entry_size > 1 && (sh.offset() % 8 != 0 || sh.size() % entry_size != 0))
+        {
+            return Err(LoaderError::Malformed("bad section"));
+        }
+    }
+    let symbols = match elf.find_section_by_name(".dynsym") {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+    let strings = elf
+        .find_section_by_name(".dynstr")
+        .filter(|header| header.get_type() == Ok(ShType::StrTab))
+        .ok_or(LoaderError::Malformed("no .dynstr section"))?
+        .raw_data(elf);
+    if let SectionData::DynSymbolTable64(symbols) = symbols.get_data(elf)? {
+        if !symbols
+            .iter()
+            .all(|symbol| valid_str(strings, symbol.name()))
+        {
+            return Err(LoaderError::Malformed("bad dynamic symbol name"));
+        }
+    }
+    Ok(())
+}
+
+/// Validate the image and return the size of the area it is loaded to.
+pub(crate) fn validate(elf: &ElfFile) -> Result<usize> {
+    check_header(elf)?;
+    let area_size = check_segments(elf)?;
+    check_sections(elf)?;
+    Ok(area_size)
+}
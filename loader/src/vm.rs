@@ -6,7 +6,7 @@ use memory_addr::VirtAddr;
 bitflags::bitflags! {
     /// Generic page table entry flags that indicate the corresponding mapped
     /// memory region permissions and attributes.
-    #[derive(Debug,Copy, Clone)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct DomainMappingFlags: usize {
         /// The memory is readable.
         const READ          = 1 << 0;
@@ -27,5 +27,17 @@ pub trait DomainArea: Send + Sync + Debug + Any {
 pub trait DomainVmOps {
     fn map_domain_area(size: usize) -> Box<dyn DomainArea>;
     fn unmap_domain_area(area: Box<dyn DomainArea>);
+    /// Make the pages read-only and executable.
     fn set_memory_x(start: usize, pages: usize) -> Result<(), &'static str>;
+    /// Make the pages read-only, such as the read-only data and the RELRO pages after the
+    /// relocation. By default it fails, so a domain is not loaded with its read-only
+    /// pages left writable.
+    fn set_memory_ro(_start: usize, _pages: usize) -> Result<(), &'static str> {
+        Err("read-only mappings not supported")
+    }
+    /// Look up a symbol the kernel exports to domains, the undefined symbols of a domain
+    /// are resolved with it.
+    fn kernel_symbol(_name: &str) -> Option<usize> {
+        None
+    }
 }
@@ -1,17 +1,18 @@
 //! DBox is a reference counted reference type that is used to share data between domains.
 //!
 //! Reference: https://std-dev-guide.rust-lang.org/policy/specialization.html
-use alloc::collections::BTreeMap;
+use alloc::collections::{btree_map::Entry, BTreeMap};
 use core::{
     alloc::Layout,
     any::TypeId,
     fmt::{Debug, Formatter},
+    mem::{ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut},
 };
 
 use spin::Mutex;
 
-use super::{CustomDrop, RRefable, SharedData, TypeIdentifiable};
+use super::{CustomDrop, Lendable, RRefable, SharedData, TypeIdentifiable};
 
 #[repr(C)]
 pub struct DBox<T>
@@ -27,78 +28,100 @@ unsafe impl<T: RRefable> RRefable for DBox<T> {}
 unsafe impl<T: RRefable> Send for DBox<T> where T: Send {}
 unsafe impl<T: RRefable> Sync for DBox<T> where T: Sync {}
 
-pub fn drop_no_type<T: CustomDrop>(ptr: *mut u8) {
+pub fn drop_no_type<T: CustomDrop>(ptr: *mut u8, _layout: Layout) {
     let ptr = ptr as *mut T;
     unsafe { &mut *ptr }.custom_drop();
 }
 
-type DropFn = fn(ptr: *mut u8);
+/// The drop function of uninitialized data, which owns nothing.
+fn drop_uninit(_ptr: *mut u8, _layout: Layout) {}
+
+type DropFn = fn(ptr: *mut u8, layout: Layout);
 static DROP: Mutex<BTreeMap<TypeId, DropFn>> = Mutex::new(BTreeMap::new());
 
-pub fn drop_domain_share_data(id: TypeId, ptr: *mut u8) {
-    let drop = DROP.lock();
-    let drop_fn = drop.get(&id).unwrap();
-    drop_fn(ptr);
+pub fn drop_domain_share_data(id: TypeId, ptr: *mut u8, layout: Layout) {
+    let drop_fn = *DROP.lock().get(&id).unwrap();
+    drop_fn(ptr, layout);
 }
 
 impl<T: RRefable> DBox<T>
 where
     T: TypeIdentifiable,
 {
-    pub(crate) unsafe fn new_with_layout(value: T, layout: Layout, init: bool) -> DBox<T> {
-        let type_id = T::type_id();
+    /// Allocate uninitialized memory with the given layout.
+    ///
+    /// The allocation is dropped as a `T` once [`DBox::assume_init`] is called,
+    /// before that the crash cleanup frees it without dropping anything.
+    pub(crate) fn new_uninit_with_layout(layout: Layout) -> DBox<MaybeUninit<T>> {
+        Self::alloc_uninit(
+            layout,
+            T::type_id(),
+            core::any::type_name::<T>(),
+            drop_no_type::<T>,
+        )
+    }
+
+    /// Allocate uninitialized memory which is released by `drop_fn`, registered
+    /// under `type_id`, once it is initialized.
+    ///
+    /// Until then the allocation has the type of `MaybeUninit<T>`, which drops
+    /// nothing, so a crash before the data is written does not drop garbage.
+    pub(crate) fn alloc_uninit(
+        layout: Layout,
+        type_id: TypeId,
+        type_name: &'static str,
+        drop_fn: DropFn,
+    ) -> DBox<MaybeUninit<T>> {
+        let uninit_type_id = TypeId::of::<MaybeUninit<T>>();
         let mut drop_guard = DROP.lock();
-        drop_guard.entry(type_id).or_insert(drop_no_type::<T>);
+        for (type_id, type_name, drop_fn) in [
+            (type_id, type_name, drop_fn),
+            (
+                uninit_type_id,
+                core::any::type_name::<MaybeUninit<T>>(),
+                drop_uninit as DropFn,
+            ),
+        ] {
+            if let Entry::Vacant(entry) = drop_guard.entry(type_id) {
+                entry.insert(drop_fn);
+                crate::share_heap_register_type(type_id, type_name);
+            }
+        }
         drop(drop_guard);
 
-        let allocation = match crate::share_heap_alloc(layout, type_id, drop_domain_share_data) {
-            Some(allocation) => allocation,
-            None => panic!("Shared heap allocation failed"),
-        };
-        let value_pointer = allocation.value_pointer as *mut T;
-        *allocation.domain_id_pointer = crate::domain_id();
-        if init {
-            core::ptr::write(value_pointer, value);
-        }
+        let allocation =
+            match crate::share_heap_alloc(layout, uninit_type_id, drop_domain_share_data) {
+                Some(allocation) => allocation,
+                None => panic!("Shared heap allocation failed"),
+            };
+        #[cfg(feature = "debug")]
+        crate::share_heap_set_type_name(allocation.value_pointer, type_name);
         DBox {
             domain_id_pointer: allocation.domain_id_pointer,
-            value_pointer,
+            value_pointer: allocation.value_pointer as *mut MaybeUninit<T>,
             exist: false,
         }
     }
 
     pub fn new(value: T) -> DBox<T> {
-        let layout = Layout::new::<T>();
-        unsafe { Self::new_with_layout(value, layout, true) }
+        Self::new_uninit().write(value)
     }
 
     pub fn new_aligned(value: T, align: usize) -> DBox<T> {
-        let size = core::mem::size_of::<T>();
-        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
-        unsafe { Self::new_with_layout(value, layout, true) }
+        Self::new_uninit_aligned(align).write(value)
     }
 
-    pub fn new_uninit() -> DBox<T> {
+    /// Constructs a new `DBox` with uninitialized contents.
+    pub fn new_uninit() -> DBox<MaybeUninit<T>> {
         let layout = Layout::new::<T>();
-        unsafe {
-            Self::new_with_layout(
-                core::mem::MaybeUninit::uninit().assume_init(),
-                layout,
-                false,
-            )
-        }
+        Self::new_uninit_with_layout(layout)
     }
 
-    pub fn new_uninit_aligned(align: usize) -> DBox<T> {
+    /// Constructs a new `DBox` with uninitialized contents and the given alignment.
+    pub fn new_uninit_aligned(align: usize) -> DBox<MaybeUninit<T>> {
         let size = core::mem::size_of::<T>();
         let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
-        unsafe {
-            Self::new_with_layout(
-                core::mem::MaybeUninit::uninit().assume_init(),
-                layout,
-                false,
-            )
-        }
+        Self::new_uninit_with_layout(layout)
     }
 
     pub fn domain_id(&self) -> u64 {
@@ -106,15 +129,88 @@ where
     }
 }
 
+impl<T: RRefable> DBox<MaybeUninit<T>> {
+    /// Writes the value and converts to `DBox<T>`.
+    pub fn write(mut self, value: T) -> DBox<T> {
+        (*self).write(value);
+        unsafe { self.assume_init() }
+    }
+
+    /// Converts to `DBox<T>`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the value really is in an initialized state.
+    pub unsafe fn assume_init(self) -> DBox<T> {
+        self.set_type(TypeId::of::<T>());
+        self.into_init()
+    }
+
+    /// Drop the allocation as `type_id` from now on.
+    pub(crate) fn set_type(&self, type_id: TypeId) {
+        if !self.exist {
+            crate::share_heap_set_type(self.value_pointer as *mut u8, type_id);
+        }
+    }
+
+    /// Converts to `DBox<T>` without changing the type of the allocation.
+    pub(crate) unsafe fn into_init(self) -> DBox<T> {
+        let this = ManuallyDrop::new(self);
+        DBox {
+            domain_id_pointer: this.domain_id_pointer,
+            value_pointer: this.value_pointer as *mut T,
+            exist: this.exist,
+        }
+    }
+}
+
+#[cfg(feature = "debug")]
+impl<T: RRefable> DBox<T> {
+    /// Check that the data is alive, and that the current domain owns it if it is
+    /// going to be modified or freed.
+    ///
+    /// A domain may read data it does not own if the owner lent it through a borrowed
+    /// argument such as `&DVec<u8>`, see [`crate::Lend`]. The kernel reads the data of
+    /// every domain, and every domain reads the data owned by no single domain.
+    pub(crate) fn debug_check(&self, owned: bool) {
+        if self.exist {
+            return;
+        }
+        crate::share_heap_check(self.value_pointer as *mut u8);
+        let owner = unsafe { *self.domain_id_pointer };
+        let current = crate::domain_id();
+        if owner == current {
+            return;
+        }
+        let lent = !owned
+            && (owner == crate::SHARED_DOMAIN_ID
+                || current == crate::SHARED_DOMAIN_ID
+                || crate::share_heap_is_lent(owner, current));
+        if !lent {
+            panic!(
+                "<DBox> domain {} uses DBox<{}> {:#x}, but it has been moved to domain {}",
+                current,
+                core::any::type_name::<T>(),
+                self.value_pointer as usize,
+                owner
+            );
+        }
+    }
+}
+
 impl<T: RRefable> Deref for DBox<T> {
     type Target = T;
     fn deref(&self) -> &T {
+        #[cfg(feature = "debug")]
+        self.debug_check(false);
         unsafe { &*self.value_pointer }
     }
 }
 
 impl<T: RRefable> DerefMut for DBox<T> {
     fn deref_mut(&mut self) -> &mut T {
+        #[cfg(feature = "debug")]
+        self.debug_check(true);
         unsafe { &mut *self.value_pointer }
     }
 }
@@ -124,6 +220,8 @@ impl<T: RRefable> Drop for DBox<T> {
         if self.exist {
             return;
         }
+        #[cfg(feature = "debug")]
+        self.debug_check(true);
         log::debug!("<drop> for DBox {:#x}", self.value_pointer as usize);
         self.custom_drop();
     }
@@ -134,6 +232,10 @@ impl<T: RRefable> CustomDrop for DBox<T> {
         if self.exist {
             return;
         }
+        // the crash cleanup drops the value itself, not through its owner
+        if crate::share_heap_is_dying(self.value_pointer as *mut u8) {
+            return;
+        }
         log::debug!("<custom_drop> for DBox {:#x}", self.value_pointer as usize);
         let value = unsafe { &mut *self.value_pointer };
         value.custom_drop();
@@ -152,12 +254,21 @@ impl<T: RRefable + Debug> Debug for DBox<T> {
     }
 }
 
+impl<T: RRefable> Lendable for DBox<T> {
+    fn owner(&self) -> Option<u64> {
+        (!self.exist).then(|| self.domain_id())
+    }
+}
+
 impl<T: RRefable> SharedData for DBox<T> {
     fn move_to(&self, new_domain_id: u64) -> u64 {
-        unsafe {
-            let old_domain_id = *self.domain_id_pointer;
-            *self.domain_id_pointer = new_domain_id;
-            old_domain_id
-        }
+        let old_domain_id = if self.exist {
+            unsafe { core::ptr::replace(self.domain_id_pointer, new_domain_id) }
+        } else {
+            crate::share_heap_move_to(self.value_pointer as *mut u8, new_domain_id)
+        };
+        // the value may own shared data too
+        unsafe { &*self.value_pointer }.move_to(new_domain_id);
+        old_domain_id
     }
 }
@@ -0,0 +1,446 @@
+//! DMap is a hash map whose table lives in the shared heap, so tables such as
+//! fd tables or inode maps can be passed between domains without flattening them.
+//!
+//! Keys are hashed with a fixed hasher, every domain finds an entry in the same bucket.
+use core::{
+    alloc::Layout,
+    borrow::Borrow,
+    fmt::{Debug, Formatter},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr::addr_of_mut,
+};
+
+use super::{CustomDrop, DBox, Lendable, RRefable, SharedData, TypeIdentifiable};
+
+const MIN_CAPACITY: usize = 8;
+/// set in the hash of every occupied bucket, an empty bucket has hash 0
+const OCCUPIED: u64 = 1 << 63;
+const SEED: u64 = 0x517c_c1b7_2722_0a95;
+
+/// FxHash followed by the murmur3 finalizer.
+#[derive(Default)]
+struct MapHasher {
+    hash: u64,
+}
+
+impl MapHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for MapHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut word = [0u8; 8];
+            word[..rest.len()].copy_from_slice(rest);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hash = self.hash;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
+
+fn make_hash<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = MapHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish() | OCCUPIED
+}
+
+struct Bucket<K, V> {
+    hash: u64,
+    key: MaybeUninit<K>,
+    value: MaybeUninit<V>,
+}
+
+/// The header of a table allocation, it is followed by `capacity` buckets.
+///
+/// The whole table is one allocation, so the heap drops every entry when the
+/// owner of the map crashes.
+struct RawTable<K, V> {
+    capacity: usize,
+    len: usize,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> RawTable<K, V> {
+    fn layout(capacity: usize) -> (Layout, usize) {
+        let (layout, offset) = Layout::new::<Self>()
+            .extend(Layout::array::<Bucket<K, V>>(capacity).unwrap())
+            .unwrap();
+        (layout.pad_to_align(), offset)
+    }
+
+    fn buckets(&self) -> &[Bucket<K, V>] {
+        let (_, offset) = Self::layout(self.capacity);
+        unsafe {
+            let buckets = (self as *const Self as *const u8).add(offset);
+            core::slice::from_raw_parts(buckets as *const Bucket<K, V>, self.capacity)
+        }
+    }
+
+    fn buckets_mut(&mut self) -> &mut [Bucket<K, V>] {
+        let (_, offset) = Self::layout(self.capacity);
+        unsafe {
+            let buckets = (self as *mut Self as *mut u8).add(offset);
+            core::slice::from_raw_parts_mut(buckets as *mut Bucket<K, V>, self.capacity)
+        }
+    }
+
+    /// Stores an entry whose key is not in the table yet.
+    fn insert_unique(&mut self, hash: u64, key: K, value: V) {
+        let mask = self.capacity - 1;
+        let buckets = self.buckets_mut();
+        let mut index = hash as usize & mask;
+        while buckets[index].hash != 0 {
+            index = (index + 1) & mask;
+        }
+        buckets[index] = Bucket {
+            hash,
+            key: MaybeUninit::new(key),
+            value: MaybeUninit::new(value),
+        };
+        self.len += 1;
+    }
+}
+
+impl<K: RRefable + 'static, V: RRefable + 'static> RawTable<K, V> {
+    fn allocate(capacity: usize) -> DBox<Self> {
+        let (layout, offset) = Self::layout(capacity);
+        let table = DBox::<Self>::new_uninit_with_layout(layout);
+        unsafe {
+            let ptr = table.value_pointer as *mut Self;
+            ptr.write(RawTable {
+                capacity,
+                len: 0,
+                _marker: PhantomData,
+            });
+            let buckets = (ptr as *mut u8).add(offset) as *mut Bucket<K, V>;
+            for i in 0..capacity {
+                addr_of_mut!((*buckets.add(i)).hash).write(0);
+            }
+            table.assume_init()
+        }
+    }
+}
+
+/// The table of a map, the map may be modified by a domain it has been lent to, so the
+/// owner check of `DBox` does not apply.
+fn raw_table<K: RRefable, V: RRefable>(table: &mut DBox<RawTable<K, V>>) -> &mut RawTable<K, V> {
+    unsafe { &mut *table.value_pointer }
+}
+
+impl<K: RRefable, V: RRefable> CustomDrop for RawTable<K, V> {
+    fn custom_drop(&mut self) {
+        for bucket in self.buckets_mut() {
+            if bucket.hash != 0 {
+                unsafe {
+                    bucket.key.assume_init_mut().custom_drop();
+                    bucket.value.assume_init_mut().custom_drop();
+                }
+                bucket.hash = 0;
+            }
+        }
+        self.len = 0;
+    }
+}
+
+impl<K: RRefable, V: RRefable> SharedData for RawTable<K, V> {
+    fn move_to(&self, new_domain_id: u64) -> u64 {
+        for bucket in self.buckets() {
+            if bucket.hash != 0 {
+                unsafe {
+                    bucket.key.assume_init_ref().move_to(new_domain_id);
+                    bucket.value.assume_init_ref().move_to(new_domain_id);
+                }
+            }
+        }
+        0
+    }
+}
+
+pub struct DMap<K, V>
+where
+    K: 'static + RRefable + Hash + Eq,
+    V: 'static + RRefable,
+{
+    /// allocated by the first insertion
+    table: Option<DBox<RawTable<K, V>>>,
+}
+
+impl<K, V> DMap<K, V>
+where
+    K: 'static + RRefable + Hash + Eq + TypeIdentifiable,
+    V: 'static + RRefable + TypeIdentifiable,
+{
+    pub const fn new() -> Self {
+        Self { table: None }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut map = Self::new();
+        map.reserve(capacity);
+        map
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.as_ref().map_or(0, |table| table.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of entries the map can hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.table
+            .as_ref()
+            .map_or(0, |table| table.capacity / 4 * 3)
+    }
+
+    /// Makes room for at least `additional` more entries.
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len() + additional;
+        if needed <= self.capacity() {
+            return;
+        }
+        let capacity = (needed * 4 / 3 + 1).next_power_of_two().max(MIN_CAPACITY);
+        self.resize(capacity);
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        let mut new = RawTable::<K, V>::allocate(capacity);
+        if let Some(mut old) = self.table.take() {
+            // the map may be used by a domain it has been lent to
+            let owner = old.domain_id();
+            if owner != crate::domain_id() {
+                crate::share_heap_move_to(new.value_pointer as *mut u8, owner);
+            }
+            let table = raw_table(&mut new);
+            for bucket in raw_table(&mut old).buckets_mut() {
+                if bucket.hash != 0 {
+                    let (key, value) = unsafe {
+                        (
+                            bucket.key.assume_init_read(),
+                            bucket.value.assume_init_read(),
+                        )
+                    };
+                    table.insert_unique(bucket.hash, key, value);
+                    bucket.hash = 0;
+                }
+            }
+            // the owner check of `DBox::drop` fails if the map is lent, the table is
+            // empty and freed directly
+            raw_table(&mut old).len = 0;
+            old.custom_drop();
+            core::mem::forget(old);
+        }
+        self.table = Some(new);
+    }
+
+    fn find<Q>(&self, hash: u64, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let table = self.table.as_ref()?;
+        let mask = table.capacity - 1;
+        let buckets = table.buckets();
+        let mut index = hash as usize & mask;
+        // the load factor keeps at least one bucket empty
+        loop {
+            let bucket = &buckets[index];
+            if bucket.hash == 0 {
+                return None;
+            }
+            if bucket.hash == hash && unsafe { bucket.key.assume_init_ref() }.borrow() == key {
+                return Some(index);
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.find(make_hash(key), key)?;
+        let bucket = &self.table.as_ref()?.buckets()[index];
+        Some(unsafe { bucket.value.assume_init_ref() })
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.find(make_hash(key), key)?;
+        let bucket = &mut raw_table(self.table.as_mut()?).buckets_mut()[index];
+        Some(unsafe { bucket.value.assume_init_mut() })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(make_hash(key), key).is_some()
+    }
+
+    /// Inserts an entry and returns the previous value of the key.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let hash = make_hash(&key);
+        if let Some(index) = self.find(hash, &key) {
+            let bucket = &mut raw_table(self.table.as_mut().unwrap()).buckets_mut()[index];
+            let old = unsafe { bucket.value.assume_init_mut() };
+            return Some(core::mem::replace(old, value));
+        }
+        self.reserve(1);
+        raw_table(self.table.as_mut().unwrap()).insert_unique(hash, key, value);
+        None
+    }
+
+    /// Removes a key and returns its value.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.find(make_hash(key), key)?;
+        let table = raw_table(self.table.as_mut()?);
+        table.len -= 1;
+        let mask = table.capacity - 1;
+        let buckets = table.buckets_mut();
+        let (key, value) = unsafe {
+            (
+                buckets[index].key.assume_init_read(),
+                buckets[index].value.assume_init_read(),
+            )
+        };
+        buckets[index].hash = 0;
+        // shift the following entries back, so that lookups never stop at the hole
+        let mut hole = index;
+        let mut next = (hole + 1) & mask;
+        while buckets[next].hash != 0 {
+            let ideal = buckets[next].hash as usize & mask;
+            if next.wrapping_sub(ideal) & mask >= next.wrapping_sub(hole) & mask {
+                buckets[hole] = unsafe { core::ptr::read(&buckets[next]) };
+                buckets[next].hash = 0;
+                hole = next;
+            }
+            next = (next + 1) & mask;
+        }
+        drop(key);
+        Some(value)
+    }
+
+    /// Removes all entries, keeping the table.
+    pub fn clear(&mut self) {
+        if let Some(table) = self.table.as_mut() {
+            // the entries are dropped as when the map is dropped
+            raw_table(table).custom_drop();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.table
+            .iter()
+            .flat_map(|table| table.buckets().iter())
+            .filter(|bucket| bucket.hash != 0)
+            .map(|bucket| unsafe { (bucket.key.assume_init_ref(), bucket.value.assume_init_ref()) })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.table
+            .iter_mut()
+            .flat_map(|table| raw_table(table).buckets_mut().iter_mut())
+            .filter(|bucket| bucket.hash != 0)
+            .map(|bucket| unsafe { (bucket.key.assume_init_ref(), bucket.value.assume_init_mut()) })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<K, V> Default for DMap<K, V>
+where
+    K: 'static + RRefable + Hash + Eq + TypeIdentifiable,
+    V: 'static + RRefable + TypeIdentifiable,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for DMap<K, V>
+where
+    K: 'static + RRefable + Hash + Eq + TypeIdentifiable + Debug,
+    V: 'static + RRefable + TypeIdentifiable + Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: RRefable + Hash + Eq, V: RRefable> CustomDrop for DMap<K, V> {
+    fn custom_drop(&mut self) {
+        log::debug!("<custom_drop> for DMap");
+        if let Some(table) = self.table.as_mut() {
+            table.custom_drop();
+        }
+    }
+}
+
+impl<K: RRefable + Hash + Eq, V: RRefable> Lendable for DMap<K, V> {
+    fn owner(&self) -> Option<u64> {
+        self.table.as_ref().and_then(Lendable::owner)
+    }
+}
+
+impl<K: RRefable + Hash + Eq, V: RRefable> SharedData for DMap<K, V> {
+    fn move_to(&self, new_domain_id: u64) -> u64 {
+        self.table
+            .as_ref()
+            .map_or(0, |table| table.move_to(new_domain_id))
+    }
+}
@@ -0,0 +1,469 @@
+//! DRing is a fixed-capacity ring buffer in the shared heap, used to stream `Copy` data
+//! between domains without a proxy call per element.
+//!
+//! The ring is owned by [`SHARED_DOMAIN_ID`] and is released with its last endpoint.
+//! The endpoints are ordinary shared data, so when a domain crashes the heap drops the
+//! endpoints it owns and the other side observes the ring as closed.
+use core::{
+    cell::UnsafeCell,
+    cmp,
+    hint::spin_loop,
+    marker::PhantomData,
+    mem::{ManuallyDrop, MaybeUninit},
+    ptr::addr_of_mut,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+use spin::Once;
+
+use crate::{CustomDrop, DBox, RRefable, SharedData, SHARED_DOMAIN_ID};
+
+/// The scheduler operations used to block on a ring.
+pub trait RingWaiter: Send + Sync {
+    /// Returns the id of the current task, or `None` if there is no task to block.
+    fn current_tid(&self) -> Option<usize>;
+    /// Blocks the current task until it is woken up.
+    fn wait(&self);
+    /// Wakes up the task with the given id.
+    fn wake(&self, tid: usize);
+}
+
+static RING_WAITER: Once<&'static dyn RingWaiter> = Once::new();
+
+/// Sets the waiter used by the blocking operations of the rings in this domain.
+///
+/// Without a waiter, `push` and `pop` spin until they can make progress, and the tasks
+/// blocked on the other side of a ring are not woken up when an endpoint is dropped here.
+pub fn init_ring_waiter(waiter: &'static dyn RingWaiter) {
+    RING_WAITER.call_once(|| waiter);
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Whether a ring accepts one producer or many.
+pub trait RingMode: private::Sealed + 'static {
+    const MULTI_PRODUCER: bool;
+}
+
+/// A single-producer single-consumer ring.
+pub struct Spsc;
+/// A multi-producer single-consumer ring, its producer can be cloned.
+pub struct Mpsc;
+
+impl private::Sealed for Spsc {}
+impl private::Sealed for Mpsc {}
+
+impl RingMode for Spsc {
+    const MULTI_PRODUCER: bool = false;
+}
+
+impl RingMode for Mpsc {
+    const MULTI_PRODUCER: bool = true;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PushError<T> {
+    /// The ring is full.
+    Full(T),
+    /// The consumer has been dropped.
+    Closed(T),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PopError {
+    /// The ring is empty.
+    Empty,
+    /// The ring is empty and all producers have been dropped.
+    Closed,
+}
+
+/// Number of producers which can sleep on a full ring at the same time,
+/// other producers spin.
+const PRODUCER_WAITERS: usize = 4;
+const NO_WAITER: usize = 0;
+
+/// The state shared by the endpoints, it sits at the start of every ring so
+/// that the endpoints can release it without knowing the element type.
+#[repr(C)]
+struct RingHeader {
+    /// live endpoints, the ring is freed with the last one
+    endpoints: AtomicUsize,
+    producers: AtomicUsize,
+    consumers: AtomicUsize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// sleeping tasks are stored as tid + 1
+    consumer_waiter: AtomicUsize,
+    producer_waiters: [AtomicUsize; PRODUCER_WAITERS],
+}
+
+impl RingHeader {
+    fn wake_consumer(&self) {
+        wake(&self.consumer_waiter);
+    }
+
+    fn wake_producers(&self) {
+        self.producer_waiters.iter().for_each(wake);
+    }
+}
+
+fn wake(waiter: &AtomicUsize) {
+    let tid = waiter.swap(NO_WAITER, Ordering::SeqCst);
+    if tid != NO_WAITER {
+        if let Some(ring_waiter) = RING_WAITER.get() {
+            ring_waiter.wake(tid - 1);
+        }
+    }
+}
+
+/// Sleeps in one of the waiter slots unless `ready` holds once the task is
+/// registered. The caller retries its operation afterwards.
+fn wait_until(waiters: &[AtomicUsize], ready: impl Fn() -> bool) {
+    let ring_waiter = RING_WAITER.get();
+    let tid = match ring_waiter.and_then(|ring_waiter| ring_waiter.current_tid()) {
+        Some(tid) => tid + 1,
+        None => {
+            spin_loop();
+            return;
+        }
+    };
+    let waiter = waiters.iter().find(|waiter| {
+        waiter.load(Ordering::SeqCst) == tid
+            || waiter
+                .compare_exchange(NO_WAITER, tid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    });
+    let Some(waiter) = waiter else {
+        spin_loop();
+        return;
+    };
+    // the other side may have made progress before we were registered
+    if ready() {
+        let _ = waiter.compare_exchange(tid, NO_WAITER, Ordering::SeqCst, Ordering::SeqCst);
+        return;
+    }
+    ring_waiter.unwrap().wait();
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A ring of `N` elements in the shared heap, `N` must be a power of two.
+///
+/// It is only reachable through its endpoints, created by [`DRing::spsc`] and
+/// [`DRing::mpsc`]:
+///
+/// ```ignore
+/// let (mut producer, mut consumer) = DRing::<u8, 256>::spsc();
+/// uart_domain.register_rx_ring(producer)?;
+/// while let Ok(byte) = consumer.pop() {
+///     // ...
+/// }
+/// ```
+#[repr(C)]
+pub struct DRing<T, const N: usize> {
+    header: RingHeader,
+    slots: [Slot<T>; N],
+}
+
+impl<T: Copy + RRefable + 'static, const N: usize> DRing<T, N> {
+    /// Creates a single-producer single-consumer ring.
+    pub fn spsc() -> (DRingProducer<T, N, Spsc>, DRingConsumer<T, N>) {
+        let ring = Self::create();
+        (
+            DRingProducer::from_ring(ring),
+            DRingConsumer::from_ring(ring),
+        )
+    }
+
+    /// Creates a multi-producer single-consumer ring.
+    pub fn mpsc() -> (DRingProducer<T, N, Mpsc>, DRingConsumer<T, N>) {
+        let ring = Self::create();
+        (
+            DRingProducer::from_ring(ring),
+            DRingConsumer::from_ring(ring),
+        )
+    }
+
+    /// Allocates the ring with one producer and one consumer and returns its address.
+    fn create() -> usize {
+        const {
+            assert!(
+                N.is_power_of_two(),
+                "the capacity of a DRing must be a power of two"
+            )
+        };
+        let ring = DBox::<Self>::new_uninit();
+        let ptr = ring.value_pointer as *mut Self;
+        unsafe {
+            addr_of_mut!((*ptr).header).write(RingHeader {
+                endpoints: AtomicUsize::new(2),
+                producers: AtomicUsize::new(1),
+                consumers: AtomicUsize::new(1),
+                head: AtomicUsize::new(0),
+                tail: AtomicUsize::new(0),
+                consumer_waiter: AtomicUsize::new(NO_WAITER),
+                producer_waiters: [const { AtomicUsize::new(NO_WAITER) }; PRODUCER_WAITERS],
+            });
+            let slots = addr_of_mut!((*ptr).slots) as *mut Slot<T>;
+            for i in 0..N {
+                slots.add(i).write(Slot {
+                    sequence: AtomicUsize::new(i),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                });
+            }
+        }
+        let ring = unsafe { ring.assume_init() };
+        // the ring outlives a crash of either side, the endpoints free it
+        ring.move_to(SHARED_DOMAIN_ID);
+        ManuallyDrop::new(ring).value_pointer as usize
+    }
+
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        &self.slots[pos & (N - 1)]
+    }
+
+    /// Whether the slot at the tail has not been consumed yet.
+    fn is_full(&self) -> bool {
+        let tail = self.header.tail.load(Ordering::Acquire);
+        self.slot(tail).sequence.load(Ordering::Acquire) != tail
+    }
+
+    /// Whether the slot at the head has not been produced yet.
+    fn is_empty(&self) -> bool {
+        let head = self.header.head.load(Ordering::Acquire);
+        self.slot(head).sequence.load(Ordering::Acquire) != head.wrapping_add(1)
+    }
+
+    fn len(&self) -> usize {
+        let head = self.header.head.load(Ordering::Acquire);
+        let tail = self.header.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(N)
+    }
+}
+
+/// The part of an endpoint which lives in the shared heap, so that the
+/// endpoint is released when the domain owning it crashes.
+struct RingEndpoint {
+    ring: usize,
+    producer: bool,
+}
+
+impl CustomDrop for RingEndpoint {
+    fn custom_drop(&mut self) {
+        // the endpoint releases the ring once, even if it is dropped again
+        if self.ring == 0 {
+            return;
+        }
+        let ring = core::mem::replace(&mut self.ring, 0);
+        let header = unsafe { &*(ring as *const RingHeader) };
+        if self.producer {
+            if header.producers.fetch_sub(1, Ordering::AcqRel) == 1 {
+                header.wake_consumer();
+            }
+        } else {
+            header.consumers.fetch_sub(1, Ordering::AcqRel);
+            header.wake_producers();
+        }
+        if header.endpoints.fetch_sub(1, Ordering::AcqRel) == 1 {
+            crate::share_heap_dealloc(ring as *mut u8);
+        }
+    }
+}
+
+/// The producing side of a [`DRing`].
+#[derive(CustomDrop, SharedData)]
+pub struct DRingProducer<T: Copy + RRefable + 'static, const N: usize, M: RingMode = Spsc> {
+    endpoint: DBox<RingEndpoint>,
+    _marker: PhantomData<(T, M)>,
+}
+
+impl<T: Copy + RRefable + 'static, const N: usize, M: RingMode> DRingProducer<T, N, M> {
+    fn from_ring(ring: usize) -> Self {
+        Self {
+            endpoint: DBox::new(RingEndpoint {
+                ring,
+                producer: true,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    fn ring(&self) -> &DRing<T, N> {
+        unsafe { &*(self.endpoint.ring as *const DRing<T, N>) }
+    }
+
+    /// Appends an element without blocking.
+    pub fn try_push(&mut self, value: T) -> Result<(), PushError<T>> {
+        let ring = self.ring();
+        if ring.header.consumers.load(Ordering::Acquire) == 0 {
+            return Err(PushError::Closed(value));
+        }
+        let mut pos = ring.header.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = ring.slot(pos);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence.wrapping_sub(pos) as isize).cmp(&0) {
+                // the consumer has not freed the slot yet
+                cmp::Ordering::Less => return Err(PushError::Full(value)),
+                // another producer has claimed the slot
+                cmp::Ordering::Greater => {
+                    pos = ring.header.tail.load(Ordering::Relaxed);
+                    continue;
+                }
+                cmp::Ordering::Equal => {}
+            }
+            if M::MULTI_PRODUCER {
+                if let Err(current) = ring.header.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    pos = current;
+                    continue;
+                }
+            } else {
+                ring.header
+                    .tail
+                    .store(pos.wrapping_add(1), Ordering::Relaxed);
+            }
+            unsafe { (*slot.value.get()).write(value) };
+            slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+            fence(Ordering::SeqCst);
+            ring.header.wake_consumer();
+            return Ok(());
+        }
+    }
+
+    /// Appends an element, blocking while the ring is full.
+    ///
+    /// Fails only if the consumer has been dropped.
+    pub fn push(&mut self, mut value: T) -> Result<(), PushError<T>> {
+        loop {
+            match self.try_push(value) {
+                Err(PushError::Full(v)) => {
+                    value = v;
+                    let ring = self.ring();
+                    wait_until(&ring.header.producer_waiters, || {
+                        !ring.is_full() || ring.header.consumers.load(Ordering::Acquire) == 0
+                    });
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Whether the consumer has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.ring().header.consumers.load(Ordering::Acquire) == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: Copy + RRefable + 'static, const N: usize> Clone for DRingProducer<T, N, Mpsc> {
+    fn clone(&self) -> Self {
+        let header = &self.ring().header;
+        header.producers.fetch_add(1, Ordering::Relaxed);
+        header.endpoints.fetch_add(1, Ordering::Relaxed);
+        Self::from_ring(self.endpoint.ring)
+    }
+}
+
+/// The consuming side of a [`DRing`].
+#[derive(CustomDrop, SharedData)]
+pub struct DRingConsumer<T: Copy + RRefable + 'static, const N: usize> {
+    endpoint: DBox<RingEndpoint>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy + RRefable + 'static, const N: usize> DRingConsumer<T, N> {
+    fn from_ring(ring: usize) -> Self {
+        Self {
+            endpoint: DBox::new(RingEndpoint {
+                ring,
+                producer: false,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    fn ring(&self) -> &DRing<T, N> {
+        unsafe { &*(self.endpoint.ring as *const DRing<T, N>) }
+    }
+
+    /// Removes the oldest element without blocking.
+    pub fn try_pop(&mut self) -> Result<T, PopError> {
+        let ring = self.ring();
+        loop {
+            let pos = ring.header.head.load(Ordering::Relaxed);
+            let slot = ring.slot(pos);
+            if slot.sequence.load(Ordering::Acquire) == pos.wrapping_add(1) {
+                let value = unsafe { (*slot.value.get()).assume_init_read() };
+                slot.sequence.store(pos.wrapping_add(N), Ordering::Release);
+                ring.header
+                    .head
+                    .store(pos.wrapping_add(1), Ordering::Release);
+                fence(Ordering::SeqCst);
+                ring.header.wake_producers();
+                return Ok(value);
+            }
+            if ring.header.producers.load(Ordering::Acquire) != 0 {
+                return Err(PopError::Empty);
+            }
+            // the last producer may have pushed before it was dropped
+            if ring.is_empty() {
+                return Err(PopError::Closed);
+            }
+        }
+    }
+
+    /// Removes the oldest element, blocking while the ring is empty.
+    ///
+    /// Fails only if the ring is empty and all producers have been dropped.
+    pub fn pop(&mut self) -> Result<T, PopError> {
+        loop {
+            match self.try_pop() {
+                Err(PopError::Empty) => {
+                    let ring = self.ring();
+                    wait_until(core::slice::from_ref(&ring.header.consumer_waiter), || {
+                        !ring.is_empty() || ring.header.producers.load(Ordering::Acquire) == 0
+                    });
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Whether all producers have been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.ring().header.producers.load(Ordering::Acquire) == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring().is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
@@ -1,70 +1,101 @@
 use alloc::boxed::Box;
 use core::{
     alloc::Layout,
+    any::TypeId,
     fmt::{Debug, Formatter},
-    mem::MaybeUninit,
+    mem::{ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut, Index, IndexMut},
 };
 
-use super::{CustomDrop, DBox, RRefable, SharedData, TypeIdentifiable};
+use super::{CustomDrop, DBox, Lendable, RRefable, SharedData, TypeIdentifiable};
 
 pub struct DVec<T>
 where
-    T: 'static + RRefable + Copy + TypeIdentifiable,
+    T: 'static + RRefable + TypeIdentifiable,
 {
-    data: DBox<T>,
+    /// the elements are dropped by the vector, not by the box
+    data: ManuallyDrop<DBox<T>>,
     size: usize,
     exist: bool,
 }
-unsafe impl<T> RRefable for DVec<T> where T: 'static + RRefable + Copy + TypeIdentifiable {}
-unsafe impl<T> Send for DVec<T> where T: 'static + RRefable + Copy + TypeIdentifiable {}
+unsafe impl<T> RRefable for DVec<T> where T: 'static + RRefable + TypeIdentifiable {}
+unsafe impl<T> Send for DVec<T> where T: 'static + RRefable + TypeIdentifiable + Send {}
+
+/// Drops or moves every element of a vector. `Copy` elements cannot own
+/// shared data, so large byte buffers are not walked.
+trait Elements: Sized {
+    fn drop_elements(elements: &mut [Self]);
+    fn move_elements(elements: &[Self], new_domain_id: u64);
+}
+
+impl<T: RRefable> Elements for T {
+    default fn drop_elements(elements: &mut [Self]) {
+        elements
+            .iter_mut()
+            .for_each(|element| element.custom_drop());
+    }
+
+    default fn move_elements(elements: &[Self], new_domain_id: u64) {
+        elements.iter().for_each(|element| {
+            element.move_to(new_domain_id);
+        });
+    }
+}
+
+impl<T: RRefable + Copy> Elements for T {
+    fn drop_elements(_elements: &mut [Self]) {}
+    fn move_elements(_elements: &[Self], _new_domain_id: u64) {}
+}
+
+/// The drop function of a vector allocation, the number of elements is
+/// recovered from the layout of the allocation.
+fn drop_slice_no_type<T: RRefable>(ptr: *mut u8, layout: Layout) {
+    let size = match core::mem::size_of::<T>() {
+        0 => return,
+        element => layout.size() / element,
+    };
+    let elements = unsafe { core::slice::from_raw_parts_mut(ptr as *mut T, size) };
+    T::drop_elements(elements);
+}
 
 impl<T> DVec<T>
 where
-    T: 'static + RRefable + Copy + TypeIdentifiable,
+    T: 'static + RRefable + TypeIdentifiable,
 {
-    pub fn new(initial_value: T, size: usize) -> Self {
+    /// Constructs a new `DVec` of `size` elements with uninitialized contents.
+    pub fn new_uninit(size: usize) -> DVec<MaybeUninit<T>> {
         let layout = Layout::array::<T>(size).unwrap();
-        let data = unsafe { DBox::new_with_layout(initial_value, layout, false) };
-        let mut vec = Self {
-            data,
-            size,
-            exist: false,
-        };
-        vec.as_mut_slice().fill(initial_value);
-        vec
-    }
-
-    pub fn new_uninit(size: usize) -> Self {
-        let layout = Layout::array::<T>(size).unwrap();
-        let data =
-            unsafe { DBox::new_with_layout(MaybeUninit::uninit().assume_init(), layout, false) };
-        Self {
-            data,
+        let data = DBox::<T>::alloc_uninit(
+            layout,
+            TypeId::of::<[T]>(),
+            core::any::type_name::<[T]>(),
+            drop_slice_no_type::<T>,
+        );
+        DVec {
+            data: ManuallyDrop::new(data),
             size,
             exist: false,
         }
     }
 
-    #[allow(clippy::uninit_assumed_init)]
-    pub fn from_slice(slice: &[T]) -> Self {
-        let size = slice.len();
-        let layout = Layout::array::<T>(size).unwrap();
-        let data =
-            unsafe { DBox::new_with_layout(MaybeUninit::uninit().assume_init(), layout, false) };
-        let mut vec = Self {
-            data,
-            size,
-            exist: false,
-        };
-        vec.as_mut_slice().copy_from_slice(slice);
-        vec
+    /// Constructs a new `DVec` of `size` elements, the element at each index is returned by
+    /// `f`. Unlike [`DVec::new`] the elements need not be `Clone`, such as `DBox`es.
+    pub fn from_fn<F: FnMut(usize) -> T>(size: usize, mut f: F) -> Self {
+        let mut vec = Self::new_uninit(size);
+        vec.as_mut_slice()
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, element)| {
+                element.write(f(index));
+            });
+        unsafe { vec.assume_init() }
     }
+
     pub fn as_slice(&self) -> &[T] {
-        unsafe { core::slice::from_raw_parts(&*self.data, self.size) }
+        unsafe { core::slice::from_raw_parts(&**self.data, self.size) }
     }
     pub fn as_mut_slice(&mut self) -> &mut [T] {
-        unsafe { core::slice::from_raw_parts_mut(&mut *self.data, self.size) }
+        unsafe { core::slice::from_raw_parts_mut(&mut **self.data, self.size) }
     }
     pub fn size(&self) -> usize {
         self.size
@@ -76,7 +107,36 @@ where
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
+}
+
+impl<T> DVec<T>
+where
+    T: 'static + RRefable + Clone + TypeIdentifiable,
+{
+    pub fn new(initial_value: T, size: usize) -> Self {
+        let mut vec = Self::new_uninit(size);
+        vec.as_mut_slice().iter_mut().for_each(|element| {
+            element.write(initial_value.clone());
+        });
+        unsafe { vec.assume_init() }
+    }
 
+    pub fn from_slice(slice: &[T]) -> Self {
+        let mut vec = Self::new_uninit(slice.len());
+        vec.as_mut_slice()
+            .iter_mut()
+            .zip(slice)
+            .for_each(|(dst, src)| {
+                dst.write(src.clone());
+            });
+        unsafe { vec.assume_init() }
+    }
+}
+
+impl<T> DVec<T>
+where
+    T: 'static + RRefable + Copy + TypeIdentifiable,
+{
     /// # WARNING
     /// This is a super dangerous function, it will return a slice of the data without checking the domain id
     pub fn from_other_rvec_slice(slice: &[T]) -> Self {
@@ -88,21 +148,42 @@ where
             exist: true,
         };
         Self {
-            data: shared_heap,
+            data: ManuallyDrop::new(shared_heap),
             size: slice.len(),
             exist: true,
         }
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> Index<usize> for DVec<T> {
+impl<T> DVec<MaybeUninit<T>>
+where
+    T: 'static + RRefable + TypeIdentifiable,
+{
+    /// Converts to `DVec<T>`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that every element really is in an initialized state.
+    pub unsafe fn assume_init(self) -> DVec<T> {
+        let this = ManuallyDrop::new(self);
+        let data = core::ptr::read(&*this.data);
+        data.set_type(TypeId::of::<[T]>());
+        DVec {
+            data: ManuallyDrop::new(data.into_init()),
+            size: this.size,
+            exist: this.exist,
+        }
+    }
+}
+
+impl<T: RRefable + TypeIdentifiable> Index<usize> for DVec<T> {
     type Output = T;
     fn index(&self, index: usize) -> &Self::Output {
         &self.as_slice()[index]
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> IndexMut<usize> for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> IndexMut<usize> for DVec<T> {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         &mut self.as_mut_slice()[index]
     }
@@ -110,7 +191,7 @@ impl<T: RRefable + Copy + TypeIdentifiable> IndexMut<usize> for DVec<T> {
 
 impl<T> Debug for DVec<T>
 where
-    T: 'static + RRefable + Copy + TypeIdentifiable + Debug,
+    T: 'static + RRefable + TypeIdentifiable + Debug,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("DVec")
@@ -120,7 +201,7 @@ where
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> Drop for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> Drop for DVec<T> {
     fn drop(&mut self) {
         unsafe {
             if self.exist {
@@ -129,34 +210,62 @@ impl<T: RRefable + Copy + TypeIdentifiable> Drop for DVec<T> {
                 return;
             }
         }
+        #[cfg(feature = "debug")]
+        self.data.debug_check(true);
         log::debug!("<drop> for DVec");
+        self.custom_drop();
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> CustomDrop for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> CustomDrop for DVec<T> {
     fn custom_drop(&mut self) {
         if self.exist {
             return;
         }
+        // the crash cleanup drops the elements itself, not through their owner, except
+        // zero-sized ones whose number it cannot recover
+        if core::mem::size_of::<T>() != 0
+            && crate::share_heap_is_dying(self.data.value_pointer as *mut u8)
+        {
+            return;
+        }
         log::debug!("<custom_drop> for DVec");
-        self.data.custom_drop();
+        let elements =
+            unsafe { core::slice::from_raw_parts_mut(self.data.value_pointer, self.size) };
+        T::drop_elements(elements);
+        crate::share_heap_dealloc(self.data.value_pointer as *mut u8);
+    }
+}
+
+impl<T: RRefable + TypeIdentifiable> Lendable for DVec<T> {
+    fn owner(&self) -> Option<u64> {
+        (!self.exist).then(|| self.data.domain_id())
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> SharedData for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> SharedData for DVec<T> {
     fn move_to(&self, new_domain_id: u64) -> u64 {
-        self.data.move_to(new_domain_id)
+        let old_domain_id = if self.exist {
+            unsafe { core::ptr::replace(self.data.domain_id_pointer, new_domain_id) }
+        } else {
+            crate::share_heap_move_to(self.data.value_pointer as *mut u8, new_domain_id)
+        };
+        // the elements are no longer owned by the current domain, so they are not read
+        // through the checked `as_slice`
+        let elements = unsafe { core::slice::from_raw_parts(self.data.value_pointer, self.size) };
+        T::move_elements(elements, new_domain_id);
+        old_domain_id
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> Deref for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> Deref for DVec<T> {
     type Target = [T];
     fn deref(&self) -> &Self::Target {
         self.as_slice()
     }
 }
 
-impl<T: RRefable + Copy + TypeIdentifiable> DerefMut for DVec<T> {
+impl<T: RRefable + TypeIdentifiable> DerefMut for DVec<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.as_mut_slice()
     }
@@ -0,0 +1,46 @@
+//! Shared data lent to another domain for the length of a call.
+//!
+//! A domain reads the data of its caller through borrowed arguments such as `&DVec<u8>`,
+//! while the data stays owned by the caller. The proxies record such a borrow, so that the
+//! debug mode can tell it apart from a read of data which has been moved away.
+
+/// Shared data which can be passed by reference to another domain.
+pub trait Lendable {
+    /// The domain owning the data, `None` if it has no allocation in the shared heap.
+    fn owner(&self) -> Option<u64>;
+}
+
+/// Lets a domain read the shared data of the owner of a borrowed argument until it is
+/// dropped. The proxies create one for each argument passed by reference, it does nothing
+/// without the `debug` feature.
+pub struct Lend {
+    #[cfg(feature = "debug")]
+    owner: Option<u64>,
+    #[cfg(feature = "debug")]
+    borrower: u64,
+}
+
+impl Lend {
+    #[allow(unused_variables)]
+    pub fn new<T: Lendable + ?Sized>(data: &T, borrower: u64) -> Self {
+        #[cfg(feature = "debug")]
+        {
+            let owner = data.owner().filter(|owner| *owner != borrower);
+            if let Some(owner) = owner {
+                crate::share_heap_lend(owner, borrower);
+            }
+            Self { owner, borrower }
+        }
+        #[cfg(not(feature = "debug"))]
+        Self {}
+    }
+}
+
+#[cfg(feature = "debug")]
+impl Drop for Lend {
+    fn drop(&mut self) {
+        if let Some(owner) = self.owner {
+            crate::share_heap_give_back(owner, self.borrower);
+        }
+    }
+}
@@ -1,22 +1,113 @@
+#![feature(allocator_api)]
 #![feature(auto_traits)]
 #![feature(negative_impls)]
 #![feature(specialization)]
 #![allow(incomplete_features)]
 #![no_std]
 mod dbox;
+mod dmap;
+mod dring;
 mod dvec;
+mod lend;
+mod report;
 
 extern crate alloc;
+extern crate self as shared_heap;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, LinkedList, VecDeque},
+    rc::Rc,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
 use core::{
-    alloc::Layout,
+    alloc::{Allocator, Layout},
     any::{type_name_of_val, TypeId},
+    cell::{Cell, RefCell},
 };
 
+pub use custom_drop::{CustomDrop, SharedData};
 pub use dbox::DBox;
+pub use dmap::DMap;
+pub use dring::{
+    init_ring_waiter, DRing, DRingConsumer, DRingProducer, Mpsc, PopError, PushError, RingMode,
+    RingWaiter, Spsc,
+};
 pub use dvec::DVec;
+pub use lend::{Lend, Lendable};
+pub use report::{DomainUsage, SharedHeapReport, TypeUsage, TYPE_NAME_LEN};
 use spin::Once;
 /// A trait for types that can be shared between domains.
 ///
+/// Types which point into the private heap of a domain are not `RRefable`,
+/// because that heap is freed when the domain crashes. This covers raw
+/// pointers, references and the standard owners such as `Box`, `Vec`, `Arc`
+/// and `String`. `Cell` and `RefCell` are rejected as well, as their borrow
+/// state is not transferred with the ownership of the data.
+///
+/// ```
+/// use shared_heap::{DBox, RRefable};
+/// fn take(_: DBox<[u8; 16]>) {}
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<[u8; 16]>();
+/// assert_rrefable::<Option<(u64, usize)>>();
+/// ```
+///
+/// ```compile_fail
+/// use shared_heap::DBox;
+/// fn take(_: DBox<Vec<u8>>) {}
+/// ```
+///
+/// ```compile_fail
+/// use shared_heap::DVec;
+/// fn take(_: DVec<&'static u8>) {}
+/// ```
+///
+/// ```compile_fail
+/// use shared_heap::RRefable;
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<Box<u8>>();
+/// ```
+///
+/// ```compile_fail
+/// extern crate alloc;
+/// use alloc::sync::Arc;
+/// use shared_heap::RRefable;
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<Arc<u8>>();
+/// ```
+///
+/// ```compile_fail
+/// use shared_heap::RRefable;
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<String>();
+/// ```
+///
+/// ```compile_fail
+/// use core::cell::Cell;
+/// use shared_heap::RRefable;
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<Cell<usize>>();
+/// ```
+///
+/// ```compile_fail
+/// use core::cell::RefCell;
+/// use shared_heap::RRefable;
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<RefCell<usize>>();
+/// ```
+///
+/// ```compile_fail
+/// use shared_heap::RRefable;
+/// struct Message {
+///     id: u64,
+///     payload: Vec<u8>,
+/// }
+/// fn assert_rrefable<T: RRefable>() {}
+/// assert_rrefable::<Message>();
+/// ```
+///
 /// # Safety
 /// This trait is unsafe because it is not safe to share all types between domains.
 pub unsafe auto trait RRefable {}
@@ -27,6 +118,19 @@ impl<T> !RRefable for &T {}
 impl<T> !RRefable for &mut T {}
 impl<T> !RRefable for [T] {}
 
+impl<T: ?Sized, A: Allocator> !RRefable for Box<T, A> {}
+impl<T, A: Allocator> !RRefable for Vec<T, A> {}
+impl<T: ?Sized, A: Allocator> !RRefable for Arc<T, A> {}
+impl<T: ?Sized, A: Allocator> !RRefable for Rc<T, A> {}
+impl !RRefable for String {}
+impl<K, V, A: Allocator + Clone> !RRefable for BTreeMap<K, V, A> {}
+impl<T, A: Allocator + Clone> !RRefable for BTreeSet<T, A> {}
+impl<T, A: Allocator> !RRefable for VecDeque<T, A> {}
+impl<T, A: Allocator> !RRefable for LinkedList<T, A> {}
+impl<T, A: Allocator> !RRefable for BinaryHeap<T, A> {}
+impl<T: ?Sized> !RRefable for Cell<T> {}
+impl<T: ?Sized> !RRefable for RefCell<T> {}
+
 pub trait TypeIdentifiable {
     fn type_id() -> TypeId;
 }
@@ -107,7 +211,7 @@ pub struct SharedHeapAllocation {
     pub domain_id_pointer: *mut u64,
     pub layout: Layout,
     pub type_id: TypeId,
-    pub drop_fn: fn(TypeId, *mut u8),
+    pub drop_fn: fn(TypeId, *mut u8, Layout),
 }
 
 impl SharedHeapAllocation {
@@ -115,7 +219,7 @@ impl SharedHeapAllocation {
         unsafe { *self.domain_id_pointer }
     }
     pub fn drop_fn(&self) {
-        (self.drop_fn)(self.type_id, self.value_pointer);
+        (self.drop_fn)(self.type_id, self.value_pointer, self.layout);
     }
     pub fn set_domain_id(&self, domain_id: u64) {
         unsafe {
@@ -127,7 +231,8 @@ impl SharedHeapAllocation {
 unsafe impl Send for SharedHeapAllocation {}
 
 pub trait SharedHeapAlloc: Send + Sync {
-    /// Allocates a new heap allocation with the given layout, type_id, and drop function.
+    /// Allocates a new heap allocation with the given layout, type_id, and drop function,
+    /// owned by the given domain.
     ///
     /// # Safety
     ///
@@ -136,7 +241,8 @@ pub trait SharedHeapAlloc: Send + Sync {
         &self,
         layout: Layout,
         type_id: TypeId,
-        drop_fn: fn(TypeId, *mut u8),
+        drop_fn: fn(TypeId, *mut u8, Layout),
+        domain_id: u64,
     ) -> Option<SharedHeapAllocation>;
     /// Deallocates the heap allocation at the given pointer.
     ///
@@ -144,8 +250,71 @@ pub trait SharedHeapAlloc: Send + Sync {
     ///
     /// The caller must ensure that the pointer is valid and that the allocation was not already deallocated.
     unsafe fn dealloc(&self, ptr: *mut u8);
+    /// Transfers the heap allocation at the given pointer to another domain and returns the
+    /// previous owner.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is valid and that the allocation was not already deallocated.
+    unsafe fn move_to(&self, ptr: *mut u8, new_domain_id: u64) -> u64;
+    /// Changes the type the heap allocation at the given pointer is dropped as. It is
+    /// called when uninitialized data has been initialized.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is valid and that the allocation was not already deallocated.
+    unsafe fn set_type(&self, ptr: *mut u8, type_id: TypeId);
+    /// Returns whether the heap allocation at the given pointer belongs to a crashed domain
+    /// whose data is being freed. The cleanup drops every such allocation once, so the
+    /// shared data owning it must not drop it again.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is valid and that the allocation was not already deallocated.
+    unsafe fn is_dying(&self, ptr: *mut u8) -> bool;
+    /// Records the name of a type, so that the usage of the heap can be reported per type.
+    ///
+    /// It is called once per type by every domain allocating it.
+    fn register_type(&self, _type_id: TypeId, _type_name: &str) {}
+    /// Records the name of the type stored in the heap allocation at the given pointer.
+    ///
+    /// It is only called in the debug mode, to report which data is corrupted.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is valid.
+    unsafe fn set_type_name(&self, _ptr: *mut u8, _type_name: &str) {}
+    /// Checks that the heap allocation at the given pointer is still alive and intact,
+    /// and panics with a report otherwise.
+    ///
+    /// It is only called in the debug mode.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer was returned by [`SharedHeapAlloc::alloc`].
+    unsafe fn check(&self, _ptr: *mut u8) {}
+    /// Lets the borrower read the shared data of the owner until a matching
+    /// [`SharedHeapAlloc::give_back`]. A domain may be lent the data of several owners,
+    /// and the same data several times.
+    ///
+    /// It is only called in the debug mode, by [`Lend`].
+    fn lend(&self, _owner: u64, _borrower: u64) {}
+    /// Ends a borrow recorded by [`SharedHeapAlloc::lend`].
+    ///
+    /// It is only called in the debug mode, by [`Lend`].
+    fn give_back(&self, _owner: u64, _borrower: u64) {}
+    /// Returns whether the borrower has been lent the shared data of the owner.
+    ///
+    /// It is only called in the debug mode, when a domain reads data it does not own.
+    fn is_lent(&self, _owner: u64, _borrower: u64) -> bool {
+        true
+    }
 }
 
+/// The owner of shared data which does not belong to a single domain, such as the
+/// storage of a [`DRing`]. It is never reclaimed by the crash cleanup of a domain.
+pub const SHARED_DOMAIN_ID: u64 = u64::MAX - 1;
+
 static SHARED_HEAP: Once<&'static dyn SharedHeapAlloc> = Once::new();
 
 static CRATE_DOMAIN_ID: Once<u64> = Once::new();
@@ -158,15 +327,64 @@ pub fn init(allocator: &'static dyn SharedHeapAlloc, domain_id: u64) {
 pub fn share_heap_alloc(
     layout: Layout,
     type_id: TypeId,
-    drop_fn: fn(TypeId, *mut u8),
+    drop_fn: fn(TypeId, *mut u8, Layout),
 ) -> Option<SharedHeapAllocation> {
-    unsafe { SHARED_HEAP.get_unchecked().alloc(layout, type_id, drop_fn) }
+    unsafe {
+        SHARED_HEAP
+            .get_unchecked()
+            .alloc(layout, type_id, drop_fn, domain_id())
+    }
 }
 
 pub(crate) fn share_heap_dealloc(ptr: *mut u8) {
     unsafe { SHARED_HEAP.get_unchecked().dealloc(ptr) }
 }
 
+pub(crate) fn share_heap_move_to(ptr: *mut u8, new_domain_id: u64) -> u64 {
+    unsafe { SHARED_HEAP.get_unchecked().move_to(ptr, new_domain_id) }
+}
+
+pub(crate) fn share_heap_set_type(ptr: *mut u8, type_id: TypeId) {
+    unsafe { SHARED_HEAP.get_unchecked().set_type(ptr, type_id) }
+}
+
+pub(crate) fn share_heap_is_dying(ptr: *mut u8) -> bool {
+    unsafe { SHARED_HEAP.get_unchecked().is_dying(ptr) }
+}
+
+pub(crate) fn share_heap_register_type(type_id: TypeId, type_name: &str) {
+    unsafe {
+        SHARED_HEAP
+            .get_unchecked()
+            .register_type(type_id, type_name)
+    }
+}
+
+#[cfg(feature = "debug")]
+pub(crate) fn share_heap_set_type_name(ptr: *mut u8, type_name: &str) {
+    unsafe { SHARED_HEAP.get_unchecked().set_type_name(ptr, type_name) }
+}
+
+#[cfg(feature = "debug")]
+pub(crate) fn share_heap_check(ptr: *mut u8) {
+    unsafe { SHARED_HEAP.get_unchecked().check(ptr) }
+}
+
+#[cfg(feature = "debug")]
+pub(crate) fn share_heap_lend(owner: u64, borrower: u64) {
+    unsafe { SHARED_HEAP.get_unchecked().lend(owner, borrower) }
+}
+
+#[cfg(feature = "debug")]
+pub(crate) fn share_heap_give_back(owner: u64, borrower: u64) {
+    unsafe { SHARED_HEAP.get_unchecked().give_back(owner, borrower) }
+}
+
+#[cfg(feature = "debug")]
+pub(crate) fn share_heap_is_lent(owner: u64, borrower: u64) -> bool {
+    unsafe { SHARED_HEAP.get_unchecked().is_lent(owner, borrower) }
+}
+
 #[inline]
 pub fn domain_id() -> u64 {
     unsafe { *CRATE_DOMAIN_ID.get_unchecked() }
@@ -0,0 +1,90 @@
+//! The usage report of the shared heap. It is built by the kernel and lives in the
+//! shared heap itself, so it can be handed to a monitoring domain.
+use core::{
+    any::TypeId,
+    fmt::{Debug, Formatter},
+};
+
+use super::{CustomDrop, DVec, SharedData};
+
+/// Type names longer than this are truncated.
+pub const TYPE_NAME_LEN: usize = 96;
+
+/// The live objects of one type owned by a domain.
+#[derive(Copy, Clone)]
+pub struct TypeUsage {
+    pub type_id: TypeId,
+    pub objects: usize,
+    pub bytes: usize,
+    name: [u8; TYPE_NAME_LEN],
+    name_len: usize,
+}
+
+impl TypeUsage {
+    pub fn new(type_id: TypeId, type_name: &str, objects: usize, bytes: usize) -> Self {
+        let mut name_len = type_name.len().min(TYPE_NAME_LEN);
+        while !type_name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        let mut name = [0; TYPE_NAME_LEN];
+        name[..name_len].copy_from_slice(&type_name.as_bytes()[..name_len]);
+        Self {
+            type_id,
+            objects,
+            bytes,
+            name,
+            name_len,
+        }
+    }
+
+    /// The name of the type, empty if no domain has registered it.
+    pub fn type_name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or_default()
+    }
+}
+
+impl Debug for TypeUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TypeUsage")
+            .field("type_name", &self.type_name())
+            .field("objects", &self.objects)
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+/// The live objects owned by a domain.
+#[derive(CustomDrop, SharedData)]
+pub struct DomainUsage {
+    pub domain_id: u64,
+    pub objects: usize,
+    pub bytes: usize,
+    pub types: DVec<TypeUsage>,
+}
+
+#[derive(CustomDrop, SharedData)]
+pub struct SharedHeapReport {
+    pub domains: DVec<DomainUsage>,
+    /// The number of free blocks kept in the caches of the heap.
+    pub free_cache_blocks: usize,
+    /// The size of the free blocks kept in the caches of the heap.
+    pub free_cache_bytes: usize,
+}
+
+impl SharedHeapReport {
+    pub fn domain(&self, domain_id: u64) -> Option<&DomainUsage> {
+        self.domains
+            .iter()
+            .find(|usage| usage.domain_id == domain_id)
+    }
+
+    /// The number of live objects in the heap.
+    pub fn objects(&self) -> usize {
+        self.domains.iter().map(|usage| usage.objects).sum()
+    }
+
+    /// The size of the live objects in the heap.
+    pub fn bytes(&self) -> usize {
+        self.domains.iter().map(|usage| usage.bytes).sum()
+    }
+}
@@ -3,23 +3,201 @@
 #![no_std]
 #![no_main]
 extern crate alloc;
+mod persist;
+
 use alloc::{boxed::Box, sync::Arc};
 use core::{
     alloc::{AllocError, Allocator, Layout},
-    any::Any,
+    any::{Any, TypeId},
+    mem::{align_of, size_of},
     ptr::NonNull,
 };
 
+use persist::{save_value, Persistence};
+pub use persist::{Persist, PersistDevice, Persisted};
 use spin::Once;
 pub trait SendAllocator: Allocator + Send + Sync {}
 type ArcValueType = Arc<dyn Any + Send + Sync, CustomStorge>;
 
 pub trait DomainDataStorage: Send + Sync {
-    fn insert(&self, key: &str, value: ArcValueType) -> Option<ArcValueType>;
-    fn get(&self, key: &str) -> Option<ArcValueType>;
-    fn remove(&self, key: &str) -> Option<ArcValueType>;
+    fn insert(&self, key: &str, value: StorageEntry) -> StorageResult<Option<StorageEntry>>;
+    fn get(&self, key: &str) -> StorageResult<Option<StorageEntry>>;
+    fn remove(&self, key: &str) -> StorageResult<Option<StorageEntry>>;
+    /// Remove the value if it was stored with the tag and no clone of it is alive outside
+    /// of the storage.
+    fn remove_unique(&self, key: &str, tag: TypeTag) -> StorageResult<Option<StorageEntry>>;
+    /// Start a transaction, the changes made until [`DomainDataStorage::commit`] are
+    /// visible to the domain but are discarded if it crashes.
+    fn begin(&self) -> StorageResult<()>;
+    fn commit(&self) -> StorageResult<()>;
+    /// Discard the changes of the open transaction.
+    fn abort(&self) -> StorageResult<()>;
+    fn in_transaction(&self) -> bool;
+    /// Open a storage namespace shared with other domains.
+    fn open_namespace(&self, name: &str) -> StorageResult<SharedStorage>;
+}
+
+/// A storage namespace opened by a domain, it is freed by the allocator of the storage on
+/// both sides.
+pub type SharedStorage = Arc<dyn DomainDataStorage, CustomStorge>;
+
+/// The type a value was stored as.
+///
+/// A new version of a domain may keep the name of a type while changing its fields, so the
+/// `TypeId` is paired with a fingerprint of the name and the layout of the type.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TypeTag {
+    type_id: TypeId,
+    fingerprint: u64,
+}
+
+impl TypeTag {
+    pub fn of<T: Any>() -> Self {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+        let bytes = core::any::type_name::<T>()
+            .bytes()
+            .chain(size_of::<T>().to_le_bytes())
+            .chain(align_of::<T>().to_le_bytes());
+        let fingerprint = bytes.fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+        Self {
+            type_id: TypeId::of::<T>(),
+            fingerprint,
+        }
+    }
+}
+
+/// A value in the domain storage, tagged with the type it was stored as.
+#[derive(Debug, Clone)]
+pub struct StorageEntry {
+    tag: TypeTag,
+    value: ArcValueType,
+    persistence: Persistence,
+}
+
+impl StorageEntry {
+    pub fn new<T: Any + Send + Sync>(value: Arc<T, CustomStorge>) -> Self {
+        Self {
+            tag: TypeTag::of::<T>(),
+            value,
+            persistence: Persistence::None,
+        }
+    }
+
+    /// A value which is written to the persistent storage.
+    pub fn persistent<T: Persist + Any + Send + Sync>(value: Arc<T, CustomStorge>) -> Self {
+        Self {
+            persistence: Persistence::Saver {
+                version: T::VERSION,
+                save: save_value::<T>,
+            },
+            ..Self::new(value)
+        }
+    }
+
+    /// A value read from the persistent storage, it is loaded by the domain on its first
+    /// access.
+    pub fn restored(value: Persisted) -> Self {
+        Self {
+            persistence: Persistence::Restored,
+            ..Self::new(Arc::new_in(value, CustomStorge))
+        }
+    }
+
+    /// The restored value, if the domain has not loaded it yet.
+    ///
+    /// The kernel and the domains are built separately, so a restored value is not
+    /// recognized by its `TypeId`.
+    pub fn persisted(&self) -> Option<&Persisted> {
+        match self.persistence {
+            Persistence::Restored => unsafe {
+                Some(&*(Arc::as_ptr(&self.value) as *const Persisted))
+            },
+            _ => None,
+        }
+    }
+
+    /// The version of the persistent format of the value, `None` if it is not persistent.
+    pub fn persist_version(&self) -> Option<u32> {
+        match self.persistence {
+            Persistence::None => None,
+            Persistence::Saver { version, .. } => Some(version),
+            Persistence::Restored => self.persisted().map(|persisted| persisted.version),
+        }
+    }
+
+    /// Write the persistent value to the buffer and return the bytes written, or `None` if
+    /// the buffer is too small or the value is not persistent.
+    pub fn save(&self, buf: &mut [u8]) -> Option<usize> {
+        match self.persistence {
+            Persistence::None => None,
+            Persistence::Saver { save, .. } => save(&*self.value, buf),
+            Persistence::Restored => {
+                let data = &self.persisted()?.data;
+                buf.get_mut(..data.len())?.copy_from_slice(data);
+                Some(data.len())
+            }
+        }
+    }
+
+    pub fn tag(&self) -> TypeTag {
+        self.tag
+    }
+
+    /// The number of references to the value, including this one.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.value)
+    }
+
+    /// Whether both entries refer to the same value.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.value, &other.value)
+    }
+
+    /// Return the value if it was stored as `T`, or the entry itself otherwise.
+    pub fn downcast<T: Any + Send + Sync>(self) -> Result<Arc<T, CustomStorge>, Self> {
+        if self.tag == TypeTag::of::<T>() {
+            Ok(unsafe { self.value.downcast_unchecked::<T>() })
+        } else {
+            Err(self)
+        }
+    }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StorageError {
+    /// The value under the key was stored as another type.
+    TypeMismatch { expected: TypeTag, found: TypeTag },
+    /// The value under the key was restored from the persistent storage and has not been
+    /// loaded by `get_or_insert_persistent` yet.
+    NotLoaded,
+    /// A transaction is already open.
+    TransactionActive,
+    /// No transaction is open.
+    NoTransaction,
+    /// The value cannot be removed while clones of it are alive.
+    InUse { refs: usize },
+    /// The persistent storage device failed.
+    Io,
+    /// The persistent storage device has no room for the data.
+    NoSpace,
+    /// The data read from the persistent storage is damaged.
+    Corrupted,
+    /// The name of the database or the key is too long to be persisted.
+    TooLong,
+    /// The namespace does not exist.
+    NotFound,
+    /// The domain is not allowed to access the namespace this way.
+    PermissionDenied,
+    /// The operation is not supported by the storage, such as transactions in a shared
+    /// namespace.
+    Unsupported,
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
 /// A custom allocator which allocates memory from the custom heap
 ///
 /// This allocator is used to allocate memory for the domain's state data.
@@ -51,8 +229,10 @@ type DataStorageHeap = &'static dyn SendAllocator;
 static DATA_ALLOCATOR: Once<DataStorageHeap> = Once::new();
 
 pub fn init_data_allocator(allocator: &'static dyn SendAllocator) {
-    DATA_ALLOCATOR.call_once(|| allocator);
-    log::info!("init data allocator success");
+    DATA_ALLOCATOR.call_once(|| {
+        log::info!("init data allocator success");
+        allocator
+    });
 }
 
 pub struct StorageArg {
@@ -73,89 +253,345 @@ mod __private {
 
     use spin::Once;
 
-    use crate::{ArcValueType, CustomStorge, DataStorageHeap, DomainDataStorage};
+    use crate::{
+        CustomStorge, DomainDataStorage, Persist, SharedStorage, StorageEntry, StorageError,
+        StorageResult, TypeTag,
+    };
+
+    fn database() -> &'static dyn DomainDataStorage {
+        DATABASE.get().unwrap().as_ref()
+    }
+
+    fn mismatch<T: Any>(entry: &StorageEntry) -> StorageError {
+        StorageError::TypeMismatch {
+            expected: TypeTag::of::<T>(),
+            found: entry.tag(),
+        }
+    }
 
-    pub fn insert<T: Any + Send + Sync>(key: &str, value: T) -> Option<Arc<T, CustomStorge>> {
+    fn db_insert<T: Any + Send + Sync>(
+        db: &dyn DomainDataStorage,
+        key: &str,
+        value: T,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
         let arc = Arc::new_in(value, CustomStorge);
-        let old = DATABASE.get().unwrap().insert(key, arc);
-        let old_arc = match old {
-            Some(arc) => unsafe {
-                let v = arc.downcast_unchecked::<T>();
-                Some(v)
-            },
-            None => None,
-        };
-        old_arc
+        let old = db.insert(key, StorageEntry::new(arc))?;
+        put_back_mismatch(db, key, old)
     }
 
-    pub fn get<T: Any + Send + Sync>(key: &str) -> Option<Arc<T, CustomStorge>> {
-        let res = DATABASE
-            .get()
-            .unwrap()
-            .get(key)
-            .and_then(|arc_wrapper| unsafe {
-                let res = arc_wrapper.downcast_unchecked();
-                Some(res)
-            });
-        res
+    /// Return the replaced value, or put it back if it was stored as another type.
+    fn put_back_mismatch<T: Any + Send + Sync>(
+        db: &dyn DomainDataStorage,
+        key: &str,
+        old: Option<StorageEntry>,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        match old.map(|old| old.downcast::<T>()) {
+            Some(Ok(old)) => Ok(Some(old)),
+            Some(Err(old)) => {
+                let err = mismatch::<T>(&old);
+                db.insert(key, old)?;
+                Err(err)
+            }
+            None => Ok(None),
+        }
     }
 
-    pub fn get_or_insert<T: Any + Send + Sync, F: FnOnce() -> T>(
+    fn db_get<T: Any + Send + Sync>(
+        db: &dyn DomainDataStorage,
+        key: &str,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        match db.get(key)? {
+            // the type of a restored value is only known to the code loading it
+            Some(entry) if entry.persisted().is_some() => Err(StorageError::NotLoaded),
+            Some(entry) => entry
+                .downcast::<T>()
+                .map(Some)
+                .map_err(|entry| mismatch::<T>(&entry)),
+            None => Ok(None),
+        }
+    }
+
+    fn db_get_or_insert<T: Any + Send + Sync, F: FnOnce() -> Arc<T, CustomStorge>>(
+        db: &dyn DomainDataStorage,
         key: &str,
         f: F,
-    ) -> Arc<T, CustomStorge> {
-        let arc = get::<T>(&key);
+    ) -> StorageResult<Arc<T, CustomStorge>> {
+        let arc = db_get::<T>(db, key)?;
         match arc {
-            Some(arc) => arc,
+            Some(arc) => Ok(arc),
             None => {
                 let value = f();
-                let arc = Arc::new_in(value, CustomStorge);
-                DATABASE.get().unwrap().insert(key, arc.clone());
-                arc
+                db.insert(key, StorageEntry::new(value.clone()))?;
+                Ok(value)
             }
         }
     }
 
+    fn db_remove<T: Any + Send + Sync>(
+        db: &dyn DomainDataStorage,
+        key: &str,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        let entry = db.remove_unique(key, TypeTag::of::<T>())?;
+        log::info!("remove_data: {:?}, found: {}", key, entry.is_some());
+        entry
+            .map(|entry| entry.downcast::<T>().map_err(|entry| mismatch::<T>(&entry)))
+            .transpose()
+    }
+
+    /// Insert the value and return the old one.
+    ///
+    /// If the old value was stored as another type, it is kept and an error is returned.
+    pub fn insert<T: Any + Send + Sync>(
+        key: &str,
+        value: T,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        db_insert(database(), key, value)
+    }
+
+    /// Get the value stored under the key.
+    ///
+    /// A value restored from the persistent storage is decoded by
+    /// [`get_or_insert_persistent`], until then it fails with [`StorageError::NotLoaded`].
+    pub fn get<T: Any + Send + Sync>(key: &str) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        db_get(database(), key)
+    }
+
+    pub fn get_or_insert<T: Any + Send + Sync, F: FnOnce() -> T>(
+        key: &str,
+        f: F,
+    ) -> StorageResult<Arc<T, CustomStorge>> {
+        get_or_insert_in(key, || Arc::new_in(f(), CustomStorge))
+    }
+
     pub fn get_or_insert_in<T: Any + Send + Sync, F: FnOnce() -> Arc<T, CustomStorge>>(
         key: &str,
         f: F,
-    ) -> Arc<T, CustomStorge> {
-        let arc = get::<T>(&key);
-        match arc {
-            Some(arc) => arc,
-            None => {
-                let value = f();
-                DATABASE.get().unwrap().insert(key, value.clone());
-                value
+    ) -> StorageResult<Arc<T, CustomStorge>> {
+        db_get_or_insert(database(), key, f)
+    }
+
+    /// Like [`get_or_insert`], but a value stored as another type, for example by an older
+    /// version of the domain, is handed to `migrate`. The migrated value replaces it, or the
+    /// value made by `f` if `migrate` returns `None`.
+    pub fn get_or_insert_with_migration<T, F, M>(
+        key: &str,
+        f: F,
+        migrate: M,
+    ) -> StorageResult<Arc<T, CustomStorge>>
+    where
+        T: Any + Send + Sync,
+        F: FnOnce() -> T,
+        M: FnOnce(StorageEntry) -> Option<T>,
+    {
+        let value = match database().get(key)? {
+            Some(entry) => match entry.downcast::<T>() {
+                Ok(arc) => return Ok(arc),
+                Err(old) => {
+                    log::warn!("migrate data: {:?}, from {:?}", key, old.tag());
+                    migrate(old).unwrap_or_else(f)
+                }
+            },
+            None => f(),
+        };
+        let arc = Arc::new_in(value, CustomStorge);
+        database().insert(key, StorageEntry::new(arc.clone()))?;
+        Ok(arc)
+    }
+
+    /// Remove the value and return the only handle to it.
+    ///
+    /// It fails with [`StorageError::InUse`] while other clones of the value are alive. A
+    /// value removed in a transaction is still referenced by the storage until the commit.
+    pub fn remove<T: Any + Send + Sync>(key: &str) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        db_remove(database(), key)
+    }
+
+    /// Remove the key whether the value is in use or not, the value is dropped with its
+    /// last clone. Return whether the key existed.
+    pub fn discard(key: &str) -> StorageResult<bool> {
+        Ok(database().remove(key)?.is_some())
+    }
+
+    /// Insert a value which is written to the persistent storage and return the old one.
+    pub fn insert_persistent<T: Persist + Any + Send + Sync>(
+        key: &str,
+        value: T,
+    ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+        let arc = Arc::new_in(value, CustomStorge);
+        match database().insert(key, StorageEntry::persistent(arc))? {
+            Some(old) if old.persisted().is_some() => Ok(None),
+            old => put_back_mismatch(database(), key, old),
+        }
+    }
+
+    /// Like [`get_or_insert`] for a value which is written to the persistent storage. A
+    /// value restored after a reboot is loaded on the first access, `f` is used if it
+    /// cannot be loaded.
+    pub fn get_or_insert_persistent<T, F>(key: &str, f: F) -> StorageResult<Arc<T, CustomStorge>>
+    where
+        T: Persist + Any + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        let value = match database().get(key)? {
+            Some(entry) => match entry.persisted() {
+                Some(persisted) => {
+                    T::load(persisted.version, &persisted.data).unwrap_or_else(|| {
+                        log::warn!(
+                            "cannot load data: {:?}, version: {}",
+                            key,
+                            persisted.version
+                        );
+                        f()
+                    })
+                }
+                None => return entry.downcast::<T>().map_err(|entry| mismatch::<T>(&entry)),
+            },
+            None => f(),
+        };
+        let arc = Arc::new_in(value, CustomStorge);
+        database().insert(key, StorageEntry::persistent(arc.clone()))?;
+        Ok(arc)
+    }
+
+    /// Start a transaction. The changes made until [`commit`] are discarded if the domain
+    /// crashes, its successor sees the data of the last commit.
+    pub fn begin() -> StorageResult<()> {
+        database().begin()
+    }
+
+    pub fn commit() -> StorageResult<()> {
+        database().commit()
+    }
+
+    pub fn abort() -> StorageResult<()> {
+        database().abort()
+    }
+
+    /// Run `f` in a transaction and commit its changes if it returns `Ok`, or abort them
+    /// otherwise.
+    pub fn transaction<R, E, F>(f: F) -> StorageResult<Result<R, E>>
+    where
+        F: FnOnce() -> Result<R, E>,
+    {
+        begin()?;
+        let res = f();
+        match res {
+            Ok(_) => commit()?,
+            Err(_) => abort()?,
+        }
+        Ok(res)
+    }
+
+    /// A static of a domain whose value is kept in the domain storage, so the next version
+    /// of the domain finds it again. Declare it with [`domain_static!`](crate::domain_static).
+    pub struct DomainStatic<T: 'static> {
+        key: &'static str,
+        init: fn() -> T,
+        value: Once<Arc<T, CustomStorge>>,
+    }
+
+    impl<T: Any + Send + Sync> DomainStatic<T> {
+        #[doc(hidden)]
+        pub const fn new(key: &'static str, init: fn() -> T) -> Self {
+            Self {
+                key,
+                init,
+                value: Once::new(),
             }
         }
+
+        /// The key of the value in the domain storage.
+        pub fn key(&self) -> &'static str {
+            self.key
+        }
+
+        /// Return the value, it is taken from the domain storage on the first access or
+        /// initialized if the storage has none. A value stored as another type, by an older
+        /// version of the domain, is replaced.
+        pub fn get(&self) -> &Arc<T, CustomStorge> {
+            self.value.call_once(|| {
+                get_or_insert_with_migration(self.key, self.init, |_| None)
+                    .expect("the domain storage never denies its own domain")
+            })
+        }
+    }
+
+    impl<T: Any + Send + Sync> core::ops::Deref for DomainStatic<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            self.get()
+        }
     }
 
-    pub fn remove<T: Any + Send + Sync>(key: &str) -> Option<Arc<T, CustomStorge>> {
-        let res = DATABASE
-            .get()
-            .unwrap()
-            .remove(key)
-            .and_then(|value| unsafe {
-                let strong_count = Arc::strong_count(&value);
-                log::info!("remove_data: {:?}, ref count: {}", key, strong_count);
-                assert!(strong_count >= 2);
-                let value = value.downcast_unchecked::<T>();
-                let value = if strong_count != 2 {
-                    // decrement strong count to 2
-                    let raw = Arc::into_raw(value);
-                    for _ in 0..(strong_count - 2) {
-                        Arc::decrement_strong_count_in(raw, CustomStorge);
-                    }
-                    Arc::from_raw_in(raw, CustomStorge)
-                } else {
-                    value
-                };
-                let strong_count = Arc::strong_count(&value);
-                log::info!("remove_data: {:?}, ref count: {}", key, strong_count);
-                Some(value)
-            });
-        res
+    /// A storage namespace shared with other domains, registered by the kernel with the
+    /// access of each domain. Transactions are not supported in a shared namespace.
+    pub struct Namespace {
+        storage: SharedStorage,
+    }
+
+    impl Namespace {
+        pub fn insert<T: Any + Send + Sync>(
+            &self,
+            key: &str,
+            value: T,
+        ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+            db_insert(&*self.storage, key, value)
+        }
+
+        pub fn get<T: Any + Send + Sync>(
+            &self,
+            key: &str,
+        ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+            db_get(&*self.storage, key)
+        }
+
+        pub fn get_or_insert<T: Any + Send + Sync, F: FnOnce() -> T>(
+            &self,
+            key: &str,
+            f: F,
+        ) -> StorageResult<Arc<T, CustomStorge>> {
+            db_get_or_insert(&*self.storage, key, || Arc::new_in(f(), CustomStorge))
+        }
+
+        /// See [`remove`].
+        pub fn remove<T: Any + Send + Sync>(
+            &self,
+            key: &str,
+        ) -> StorageResult<Option<Arc<T, CustomStorge>>> {
+            db_remove(&*self.storage, key)
+        }
+
+        /// See [`discard`].
+        pub fn discard(&self, key: &str) -> StorageResult<bool> {
+            Ok(self.storage.remove(key)?.is_some())
+        }
+    }
+
+    /// Open a storage namespace shared with other domains.
+    pub fn open_namespace(name: &str) -> StorageResult<Namespace> {
+        let storage = database().open_namespace(name)?;
+        Ok(Namespace { storage })
+    }
+
+    /// Declare statics which keep their values in the domain storage across domain
+    /// updates, under the key `module_path::NAME`.
+    ///
+    /// ```ignore
+    /// domain_static! {
+    ///     static CACHE: Mutex<Cache> = Mutex::new(Cache::new());
+    /// }
+    /// ```
+    #[macro_export]
+    macro_rules! domain_static {
+        ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+            $(
+                $(#[$attr])*
+                $vis static $name: $crate::DomainStatic<$ty> = $crate::DomainStatic::new(
+                    concat!(module_path!(), "::", stringify!($name)),
+                    || $init,
+                );
+            )+
+        };
     }
 
     static DATABASE: Once<Box<dyn DomainDataStorage>> = Once::new();
@@ -0,0 +1,58 @@
+//! Values of the domain storage which are written to a block device and restored after a
+//! reboot.
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::{CustomStorge, StorageResult};
+
+/// A value which can be written to the persistent storage.
+///
+/// The buffers are owned by the caller, so no memory is passed between the kernel and
+/// the domain.
+pub trait Persist: Sized {
+    /// The version of the format written by [`Persist::save`], it is handed back to
+    /// [`Persist::load`] so older data can be converted.
+    const VERSION: u32;
+
+    /// Write the value to the buffer and return the bytes written, or `None` if the buffer
+    /// is too small.
+    fn save(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Read a value written by [`Persist::save`] of the given version.
+    fn load(version: u32, data: &[u8]) -> Option<Self>;
+}
+
+/// A region of a device which keeps the persistent storage, addressed in bytes.
+pub trait PersistDevice: Send + Sync {
+    fn capacity(&self) -> usize;
+    fn read(&self, offset: usize, buf: &mut [u8]) -> StorageResult<()>;
+    fn write(&self, offset: usize, buf: &[u8]) -> StorageResult<()>;
+    fn flush(&self) -> StorageResult<()>;
+}
+
+/// A value restored from the persistent storage which the domain has not loaded yet.
+#[derive(Debug)]
+pub struct Persisted {
+    pub version: u32,
+    pub data: Vec<u8, CustomStorge>,
+}
+
+pub(crate) type SaveFn = fn(&(dyn Any + Send + Sync), &mut [u8]) -> Option<usize>;
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Persistence {
+    None,
+    Saver {
+        version: u32,
+        save: SaveFn,
+    },
+    /// The value is a [`Persisted`].
+    Restored,
+}
+
+pub(crate) fn save_value<T: Persist + Any>(
+    value: &(dyn Any + Send + Sync),
+    buf: &mut [u8],
+) -> Option<usize> {
+    value.downcast_ref::<T>()?.save(buf)
+}